@@ -0,0 +1,148 @@
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::sync::Mutex;
+
+use crate::RenderOutput;
+
+const DEFAULT_CAPACITY: usize = 256;
+
+// Least recently used eviction, behind the render, image, mesh and color texture caches.
+// `capacity` bounds the total weight of what's kept: the number of entries, unless a weigher
+// says otherwise (the image cache counts bytes). Anything heavier than the whole capacity isn't
+// kept at all, so a capacity of 0 turns a cache off.
+pub struct Lru<K, V> {
+    entries: HashMap<K, (V, u64)>,
+    // Keys by the tick they were last used at, oldest first.
+    order: BTreeMap<u64, K>,
+    tick: u64,
+    weight: usize,
+    capacity: usize,
+    weigh: fn(&V) -> usize,
+}
+
+impl<K: Hash + Eq + Clone, V> Lru<K, V> {
+    pub fn new(capacity: usize) -> Lru<K, V> {
+        Lru::with_weigher(capacity, |_| 1)
+    }
+
+    pub fn with_weigher(capacity: usize, weigh: fn(&V) -> usize) -> Lru<K, V> {
+        Lru {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            weight: 0,
+            capacity,
+            weigh,
+        }
+    }
+
+    pub fn get<Q: Hash + Eq + ?Sized>(&mut self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+    {
+        self.tick += 1;
+        let (value, last_used) = self.entries.get_mut(key)?;
+        if let Some(key) = self.order.remove(last_used) {
+            self.order.insert(self.tick, key);
+        }
+        *last_used = self.tick;
+        Some(value)
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        self.remove(&key);
+        let weight = (self.weigh)(&value);
+        if weight > self.capacity {
+            return;
+        }
+        while self.weight + weight > self.capacity {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some((evicted, _)) = self.entries.remove(&oldest) {
+                self.weight -= (self.weigh)(&evicted);
+            }
+        }
+
+        self.tick += 1;
+        self.weight += weight;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (value, self.tick));
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let (value, last_used) = self.entries.remove(key)?;
+        self.order.remove(&last_used);
+        self.weight -= (self.weigh)(&value);
+        Some(value)
+    }
+}
+
+// A cache size from the environment, `unit` naming what it counts for the error message.
+pub fn capacity_from_env(name: &str, default: usize, unit: &str) -> usize {
+    match std::env::var(name) {
+        Ok(value) if !value.is_empty() => value
+            .parse()
+            .unwrap_or_else(|_| panic!("{} must be a number of {}", name, unit)),
+        _ => default,
+    }
+}
+
+// Finished renders by coalesce key, so popular avatars skip the render loop entirely.
+// RENDER_CACHE_SIZE sets how many are kept (0 turns the cache off); past that the least
+// recently used entry goes.
+pub struct RenderCache {
+    entries: Mutex<Lru<u64, RenderOutput>>,
+}
+
+impl RenderCache {
+    pub fn from_env() -> RenderCache {
+        let capacity = capacity_from_env("RENDER_CACHE_SIZE", DEFAULT_CAPACITY, "renders");
+        RenderCache {
+            entries: Mutex::new(Lru::new(capacity)),
+        }
+    }
+
+    pub fn get(&self, key: u64) -> Option<RenderOutput> {
+        self.entries.lock().ok()?.get(&key).cloned()
+    }
+
+    // Only successful renders are inserted, failures shouldn't stick.
+    pub fn insert(&self, key: u64, result: &RenderOutput) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.insert(key, result.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut lru = Lru::new(2);
+        lru.insert(1, "a");
+        lru.insert(2, "b");
+        assert_eq!(lru.get(&1), Some(&"a"));
+        lru.insert(3, "c");
+        assert_eq!(lru.get(&2), None);
+        assert_eq!(lru.get(&1), Some(&"a"));
+        assert_eq!(lru.get(&3), Some(&"c"));
+    }
+
+    #[test]
+    fn weighs_entries_and_skips_oversized_ones() {
+        let mut lru = Lru::with_weigher(10, |bytes: &Vec<u8>| bytes.len());
+        lru.insert("small", vec![0; 4]);
+        lru.insert("medium", vec![0; 6]);
+        lru.insert("huge", vec![0; 11]);
+        assert!(lru.get("huge").is_none());
+        assert!(lru.get("small").is_some());
+
+        lru.insert("more", vec![0; 5]);
+        assert!(lru.get("medium").is_none());
+        assert!(lru.get("small").is_some());
+    }
+}
@@ -0,0 +1,60 @@
+use image::{Rgba, RgbaImage, imageops};
+
+// Channels may differ by this much before a pixel counts as changed, so blending noise
+// along edges doesn't light up the whole outline.
+const DIFF_TOLERANCE: u8 = 8;
+const CHANGED: Rgba<u8> = Rgba([255, 0, 255, 255]);
+
+#[derive(Clone, Copy)]
+pub enum CompareMode {
+    SideBySide,
+    Diff,
+}
+
+impl CompareMode {
+    pub fn from_name(name: &str) -> Option<CompareMode> {
+        match name {
+            "sidebyside" => Some(CompareMode::SideBySide),
+            "diff" => Some(CompareMode::Diff),
+            _ => None,
+        }
+    }
+
+    pub fn combine(&self, a: &RgbaImage, b: &RgbaImage) -> RgbaImage {
+        match self {
+            CompareMode::SideBySide => side_by_side(a, b),
+            CompareMode::Diff => diff(a, b),
+        }
+    }
+}
+
+fn side_by_side(a: &RgbaImage, b: &RgbaImage) -> RgbaImage {
+    let mut out = RgbaImage::new(a.width() + b.width(), a.height().max(b.height()));
+    imageops::replace(&mut out, a, 0, 0);
+    imageops::replace(&mut out, b, a.width() as i64, 0);
+    out
+}
+
+// Unchanged pixels are kept from `a` at a quarter opacity for context, changed ones are
+// painted solid magenta.
+fn diff(a: &RgbaImage, b: &RgbaImage) -> RgbaImage {
+    let width = a.width().max(b.width());
+    let height = a.height().max(b.height());
+    let transparent = Rgba([0, 0, 0, 0]);
+
+    RgbaImage::from_fn(width, height, |x, y| {
+        let pa = a.get_pixel_checked(x, y).copied().unwrap_or(transparent);
+        let pb = b.get_pixel_checked(x, y).copied().unwrap_or(transparent);
+
+        let changed =
+            pa.0.iter()
+                .zip(pb.0.iter())
+                .any(|(ca, cb)| ca.abs_diff(*cb) > DIFF_TOLERANCE);
+
+        if changed {
+            CHANGED
+        } else {
+            Rgba([pa[0], pa[1], pa[2], pa[3] / 4])
+        }
+    })
+}
@@ -0,0 +1,74 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::error_response;
+
+// What a lookup came back empty for, named in the NOT_FOUND error codes.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum Missing {
+    User,
+    Item,
+    Outfit,
+}
+
+// Failures from the database lookups and the renderer. Render loop failures travel back over
+// the queue (and the worker socket), so everything is kept as text rather than the source error.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ServerError {
+    // A query failed, after retrying the transient errors.
+    DbError(String),
+    NotFound(Missing),
+    // An accessory mesh that couldn't be read or parsed.
+    MeshLoad(String),
+    // An image that isn't one, like a bad upload to /preview.
+    ImageDecode(String),
+    // Drawing, readback or encoding failed, or the job couldn't be rendered at all.
+    RenderFailed(String),
+    // The render this one joined didn't answer within RENDER_TIMEOUT_SECS.
+    TimedOut,
+}
+
+impl ServerError {
+    pub fn response(&self) -> rouille::Response {
+        match self {
+            ServerError::DbError(_) => error_response(503, "DATABASE_ERROR", "Database error"),
+            ServerError::NotFound(Missing::User) => {
+                error_response(404, "USER_NOT_FOUND", "User not found")
+            }
+            ServerError::NotFound(Missing::Item) => {
+                error_response(404, "ITEM_NOT_FOUND", "Item not found")
+            }
+            ServerError::NotFound(Missing::Outfit) => {
+                error_response(404, "OUTFIT_NOT_FOUND", "Outfit not found")
+            }
+            ServerError::ImageDecode(e) => {
+                error_response(400, "INVALID_IMAGE", format!("Invalid image: {}", e))
+            }
+            ServerError::MeshLoad(_) | ServerError::RenderFailed(_) => {
+                error_response(500, "RENDER_FAILED", "Render Failed")
+            }
+            ServerError::TimedOut => error_response(504, "RENDER_TIMEOUT", "Render timed out"),
+        }
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ServerError::DbError(e) => write!(f, "database error: {}", e),
+            ServerError::NotFound(missing) => write!(f, "{:?} not found", missing),
+            ServerError::MeshLoad(e) => write!(f, "mesh couldn't be loaded: {}", e),
+            ServerError::ImageDecode(e) => write!(f, "image couldn't be decoded: {}", e),
+            ServerError::RenderFailed(e) => write!(f, "render failed: {}", e),
+            ServerError::TimedOut => write!(f, "render timed out"),
+        }
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+impl From<sqlx::Error> for ServerError {
+    fn from(e: sqlx::Error) -> ServerError {
+        ServerError::DbError(e.to_string())
+    }
+}
@@ -0,0 +1,46 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::mpsc::Sender;
+
+use crate::RenderAnswer;
+
+// Coalesces identical renders that arrive while one is already queued or rendering. The first
+// request for a key becomes the leader and actually enqueues the job; everyone after it parks
+// a sender here and gets a copy of the leader's result.
+#[derive(Default)]
+pub struct InFlight {
+    waiters: Mutex<HashMap<u64, Vec<Sender<RenderAnswer>>>>,
+}
+
+impl InFlight {
+    // Returns true when the caller is the leader for this key. Followers have their sender
+    // registered and should just wait on its receiver.
+    pub fn join(&self, key: u64, sender: &Sender<RenderAnswer>) -> bool {
+        let Ok(mut waiters) = self.waiters.lock() else {
+            return true;
+        };
+
+        match waiters.get_mut(&key) {
+            Some(followers) => {
+                followers.push(sender.clone());
+                false
+            }
+            None => {
+                waiters.insert(key, Vec::new());
+                true
+            }
+        }
+    }
+
+    // Called by the leader once its render is done or has failed.
+    pub fn finish(&self, key: u64, result: &RenderAnswer) {
+        let followers = match self.waiters.lock() {
+            Ok(mut waiters) => waiters.remove(&key).unwrap_or_default(),
+            Err(_) => return,
+        };
+
+        for follower in followers {
+            let _ = follower.send(result.clone());
+        }
+    }
+}
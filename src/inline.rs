@@ -0,0 +1,105 @@
+// Avatar specs sent whole in the request body, for running the renderer without a database.
+//
+// {
+//   "colors": {"head": 1001, "trso": "#3A7BD5", ...},
+//   "accessories": [
+//     {"type": 9, "path": "hats/tophat.obj", "texture": "hats/tophat.png"},
+//     {"type": 5, "data": "<base64 PNG>"}
+//   ]
+// }
+
+use serde::Deserialize;
+
+use crate::HexBodyColors;
+use crate::utility::{BodyColors, ItemAsset, from_brickcolor, parse_hex_color, valid_scale};
+
+#[derive(Deserialize)]
+pub struct InlineSpec {
+    #[serde(default)]
+    colors: InlineColors,
+    #[serde(default)]
+    accessories: Vec<InlineAccessory>,
+}
+
+#[derive(Default, Deserialize)]
+struct InlineColors {
+    head: Option<ColorSpec>,
+    trso: Option<ColorSpec>,
+    larm: Option<ColorSpec>,
+    rarm: Option<ColorSpec>,
+    lleg: Option<ColorSpec>,
+    rleg: Option<ColorSpec>,
+}
+
+// A brickcolor id, or a hex string like "#3A7BD5".
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ColorSpec {
+    BrickColor(u16),
+    Hex(String),
+}
+
+#[derive(Deserialize)]
+struct InlineAccessory {
+    #[serde(rename = "type")]
+    item_type: i8,
+    // Relative to ASSET_BASE_PATH, like the items table. Textures may be URLs too.
+    path: Option<String>,
+    texture: Option<String>,
+    // Overrides the item's scale, for hats, shoulder, back and gear accessories.
+    scale: Option<f32>,
+    // Base64 image, only for the image based types (t-shirt, shirt, pants, face).
+    data: Option<String>,
+}
+
+impl InlineSpec {
+    pub fn resolve(self) -> Result<(HexBodyColors, Vec<ItemAsset>), String> {
+        let default = from_brickcolor(BodyColors::default().head).unwrap_or(0xFFFFFF);
+        let color =
+            |part: &str, spec: Option<ColorSpec>| match spec {
+                None => Ok(default),
+                Some(ColorSpec::BrickColor(id)) => from_brickcolor(id)
+                    .ok_or_else(|| format!("Unknown brickcolor {} for {}", id, part)),
+                Some(ColorSpec::Hex(hex)) => parse_hex_color(&hex)
+                    .ok_or_else(|| format!("Invalid color {:?} for {}", hex, part)),
+            };
+
+        let colors = HexBodyColors {
+            head: color("head", self.colors.head)?,
+            trso: color("trso", self.colors.trso)?,
+            larm: color("larm", self.colors.larm)?,
+            rarm: color("rarm", self.colors.rarm)?,
+            lleg: color("lleg", self.colors.lleg)?,
+            rleg: color("rleg", self.colors.rleg)?,
+        };
+
+        let mut accessories = Vec::new();
+        for accessory in self.accessories {
+            if accessory.data.is_some() && !(4..=7).contains(&accessory.item_type) {
+                return Err(format!(
+                    "Inline data isn't supported for item type {}",
+                    accessory.item_type
+                ));
+            }
+            if accessory.data.is_none() && accessory.path.is_none() {
+                return Err("Every accessory needs a path or data".into());
+            }
+            if let Some(scale) = accessory.scale
+                && !valid_scale(scale)
+            {
+                return Err(format!("Invalid scale {}", scale));
+            }
+
+            accessories.push(ItemAsset {
+                id: 0,
+                item_type: accessory.item_type,
+                location: accessory.path,
+                texture_path: accessory.texture,
+                scale: accessory.scale,
+                data: accessory.data,
+            });
+        }
+
+        Ok((colors, accessories))
+    }
+}
@@ -0,0 +1,22 @@
+// Logging through env_logger. Lines look like `[16-10-2026 12:00:00] INFO message`.
+//
+// RUST_LOG takes env_logger's directives: a bare level (error, warn, info, debug, trace or off)
+// sets the default, `target=level` overrides it for a module path prefix, e.g.
+// `debug,sqlx=warn`. The default is info.
+
+use chrono::Utc;
+use std::io::Write;
+
+pub fn init() {
+    let _ = env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
+        .format(|buf, record| {
+            writeln!(
+                buf,
+                "[{}] {} {}",
+                Utc::now().format("%d-%m-%Y %H:%M:%S"),
+                record.level(),
+                record.args()
+            )
+        })
+        .try_init();
+}
@@ -1,23 +1,94 @@
 use base64::Engine;
-use chrono::{DateTime, Utc};
+use chrono::Utc;
 use dotenv::dotenv;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use log::{debug, error, info, warn};
+use macroquad::miniquad::TextureWrap;
 use macroquad::prelude::*;
-use png::{BitDepth, ColorType, Encoder};
+use png::{BitDepth, ColorType, Compression, Encoder};
 use rouille::{post_input, router};
-use sqlx::mysql::MySqlPool;
-use std::sync::mpsc::{Sender, channel};
-use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use sqlx::mysql::{MySqlPool, MySqlPoolOptions};
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{
+    Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError, channel, sync_channel,
+};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{env, thread};
 
+mod cache;
+mod compare;
+mod error;
+mod inflight;
+mod inline;
+mod logger;
+mod metrics;
+mod passes;
+mod persist;
+mod poses;
+mod remote;
 mod utility;
+mod workers;
+use crate::cache::RenderCache;
+use crate::compare::CompareMode;
+use crate::error::{Missing, ServerError};
+use crate::inflight::InFlight;
+use crate::inline::InlineSpec;
+use crate::metrics::Metrics;
+use crate::passes::{PassMaterials, RenderPass};
+use crate::persist::{OutputDir, RenderInfo};
+use crate::poses::{Animation, Pose, Proportions};
 use crate::utility::{
-    BodyColors, ItemAsset, fetch_accessories_info, fetch_avatar, from_brickcolor, from_hex,
-    load_resources_and_mesh, load_static_mesh, load_static_mesh_from_bytes, process_img,
-    process_mesh, replace_transparent_with_color,
+    AssetPaths, BodyColors, ColorTextureCache, ItemAsset, MESH_CHUNK_INDICES, MESH_CHUNK_VERTICES,
+    checker_texture, detect_item_scale_column, fetch_accessories_info, fetch_avatar, fetch_outfit,
+    from_brickcolor, from_brickcolor_or_default, load_brickcolor_palette, load_denied_items,
+    load_resources_and_mesh, load_static_mesh, load_static_mesh_from_bytes, mask_ip, opaque_bounds,
+    parse_hex_color, peak_memory_kb, ping_database, prefetch_item, process_img, process_mesh,
+    replace_transparent_with_color, scale_mesh, scale_uvs, transform_mesh, valid_scale,
 };
 
 const PROGRAM_NAME: &str = "LSDBLOX Avatar Server 1.1";
-const BASE_HTTP_PATH: &str = "/srv/http";
+const RENDER_SIZE: i32 = 1024;
+// Output sizes a client may ask for. Everything is rendered at RENDER_SIZE and downscaled.
+const OUTPUT_SIZES: [u32; 4] = [150, 420, 720, 1024];
+const DEFAULT_BIND_ADDR: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+const DEFAULT_PORT: u16 = 6767;
+const HEALTH_DB_TIMEOUT: Duration = Duration::from_secs(2);
+const DEFAULT_RENDER_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_BATCH_SIZE: usize = 100;
+const DEFAULT_MAX_BUNDLE_ITEMS: usize = 20;
+const DEFAULT_PREVIEW_MAX_BYTES: usize = 8 * 1024 * 1024;
+// Avatar, item, outfit, headshot and bundle; see handle_render.
+const JOB_TYPES: std::ops::RangeInclusive<i32> = 1..=5;
+const DEFAULT_QUEUE_SIZE: usize = 64;
+const DEFAULT_SUPERSAMPLE: u32 = 2;
+// macroquad's own draw call buffer sizes, in vertices and indices.
+const DEFAULT_DRAWCALL_CAPACITY: (usize, usize) = (10000, 5000);
+// Samples per pixel in the offscreen target, resolved by the GPU before readback.
+const MSAA_SAMPLES: i32 = 4;
+const CAPTURE_ATTEMPTS: u32 = 2;
+const JPEG_DEFAULT_QUALITY: u8 = 85;
+// Transparent margin left around a trimmed render, in output pixels.
+const TRIM_PADDING: u32 = 8;
+const MAINTENANCE_RETRY_AFTER: &str = "60";
+const DEFAULT_MAX_ACCESSORIES: usize = 32;
+const DEFAULT_DB_MAX_CONNECTIONS: u32 = 10;
+const DEFAULT_DB_ACQUIRE_TIMEOUT: Duration = Duration::from_secs(30);
+
+static PNG_COMPRESSION: OnceLock<Compression> = OnceLock::new();
+// Most accessories build_scene loads for one render, from MAX_ACCESSORIES.
+static MAX_ACCESSORIES: OnceLock<usize> = OnceLock::new();
+// Default for the alpha_threshold param, from ALPHA_THRESHOLD.
+static ALPHA_THRESHOLD: OnceLock<u8> = OnceLock::new();
+// Jobs sent to the render queue that no render loop (or worker dispatcher) has taken yet.
+static QUEUE_DEPTH: AtomicUsize = AtomicUsize::new(0);
 
 const DEFAULT_MESH_BYTES: &[u8] = include_bytes!("default.obj");
 const RARM_MESH_BYTES: &[u8] = include_bytes!("rightarm.obj");
@@ -26,17 +97,22 @@ const RLEG_MESH_BYTES: &[u8] = include_bytes!("rightleg.obj");
 const LLEG_MESH_BYTES: &[u8] = include_bytes!("leftleg.obj");
 const TRSO_MESH_BYTES: &[u8] = include_bytes!("torso.obj");
 const TSHIRT_MESH_BYTES: &[u8] = include_bytes!("tshirt.obj");
+const PLACEHOLDER_MESH_BYTES: &[u8] = include_bytes!("placeholder.obj");
 
+// Every mesh in each bundled OBJ. Empty if the OBJ failed to load.
 pub struct StaticMeshes {
-    pub head: Option<tobj::Mesh>,
-    pub rarm: Option<tobj::Mesh>,
-    pub larm: Option<tobj::Mesh>,
-    pub rleg: Option<tobj::Mesh>,
-    pub lleg: Option<tobj::Mesh>,
-    pub trso: Option<tobj::Mesh>,
-    pub tshirt: Option<tobj::Mesh>,
+    pub head: Vec<tobj::Mesh>,
+    pub rarm: Vec<tobj::Mesh>,
+    pub larm: Vec<tobj::Mesh>,
+    pub rleg: Vec<tobj::Mesh>,
+    pub lleg: Vec<tobj::Mesh>,
+    pub trso: Vec<tobj::Mesh>,
+    pub tshirt: Vec<tobj::Mesh>,
+    // Checkered cube drawn where an accessory mesh couldn't be loaded.
+    pub placeholder: Vec<tobj::Mesh>,
 }
 
+#[derive(Clone, Copy, Serialize, Deserialize)]
 struct HexBodyColors {
     head: u32,
     trso: u32,
@@ -46,244 +122,2476 @@ struct HexBodyColors {
     rleg: u32,
 }
 
+#[derive(Clone, Copy, Serialize, Deserialize)]
+enum TextureFilter {
+    Linear,
+    Nearest,
+}
+
+impl From<TextureFilter> for FilterMode {
+    fn from(filter: TextureFilter) -> Self {
+        match filter {
+            TextureFilter::Linear => FilterMode::Linear,
+            TextureFilter::Nearest => FilterMode::Nearest,
+        }
+    }
+}
+
+// What shows through the transparent parts of the face decal.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+enum FaceTransparency {
+    // Head color, or the head swap's material color when one is worn.
+    Fill,
+    // Left transparent, so the head itself is see-through there.
+    Keep,
+    // Fill on the default head, keep on head swaps.
+    Auto,
+}
+
+impl FaceTransparency {
+    fn from_name(name: &str) -> Option<FaceTransparency> {
+        match name {
+            "fill" => Some(FaceTransparency::Fill),
+            "keep" => Some(FaceTransparency::Keep),
+            "auto" => Some(FaceTransparency::Auto),
+            _ => None,
+        }
+    }
+}
+
+// Encoding of the returned images. JPEG has no alpha, so those are always rendered over a
+// background.
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+enum OutputFormat {
+    #[default]
+    Png,
+    Jpeg {
+        quality: u8,
+    },
+}
+
+impl OutputFormat {
+    fn mime_type(&self) -> &'static str {
+        match self {
+            OutputFormat::Png => "image/png",
+            OutputFormat::Jpeg { .. } => "image/jpeg",
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Jpeg { .. } => "jpg",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+struct SceneOptions {
+    pose: Pose,
+    proportions: Proportions,
+    texture_filter: TextureFilter,
+    // UV scale for shirts and pants on the body. Above 1.0 the texture repeats instead of
+    // stretching once.
+    tiling: f32,
+    face_transparency: FaceTransparency,
+    // Leave the transparent parts of shirts and pants see-through instead of filling them
+    // with the body color, for previewing layered clothing.
+    keep_clothing_alpha: bool,
+    // Off leaves the head blank in the head color, for catalog renders of clothing.
+    show_face: bool,
+    // Clothing and face pixels at or below this alpha are filled as if fully transparent.
+    alpha_threshold: u8,
+}
+
+impl Default for SceneOptions {
+    fn default() -> Self {
+        Self {
+            pose: Pose::default(),
+            proportions: Proportions::default(),
+            texture_filter: TextureFilter::Linear,
+            tiling: 1.0,
+            face_transparency: FaceTransparency::Auto,
+            keep_clothing_alpha: false,
+            show_face: true,
+            alpha_threshold: ALPHA_THRESHOLD.get().copied().unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct CameraFraming {
+    yaw: f32,
+    pitch: f32,
+    radius: f32,
+    target: Vec3,
+    orthographic: bool,
+}
+
+impl CameraFraming {
+    const FULL_BODY: CameraFraming = CameraFraming {
+        yaw: 1.0,
+        pitch: 0.4,
+        radius: 10.0,
+        target: Vec3::new(-0.25, -1.75, -1.0),
+        orthographic: false,
+    };
+
+    const HEADSHOT: CameraFraming = CameraFraming {
+        yaw: 1.0,
+        pitch: 0.2,
+        radius: 4.0,
+        target: Vec3::new(0.0, 0.1, 0.0),
+        orthographic: false,
+    };
+
+    fn camera(&self) -> Camera3D {
+        let position = vec3(
+            self.radius * self.yaw.cos() * self.pitch.cos(),
+            self.radius * self.pitch.sin(),
+            self.radius * self.yaw.sin() * self.pitch.cos(),
+        ) + self.target;
+
+        let camera = Camera3D {
+            position,
+            up: vec3(0.0, 1.0, 0.0),
+            target: self.target,
+            ..Default::default()
+        };
+        if !self.orthographic {
+            return camera;
+        }
+        // For orthographic cameras fovy is the height of the view in world units. Matching what
+        // the perspective camera sees at the target keeps the framing (and zoom) the same.
+        let fovy = 2.0 * self.radius * (camera.fovy / 2.0).tan();
+        Camera3D {
+            projection: Projection::Orthographics,
+            fovy,
+            ..camera
+        }
+    }
+
+    fn adjusted(&self, angle: &CameraAngle) -> CameraFraming {
+        CameraFraming {
+            yaw: angle.yaw.unwrap_or(self.yaw) + angle.turn,
+            pitch: angle.pitch.unwrap_or(self.pitch),
+            radius: self.radius / angle.zoom.unwrap_or(1.0),
+            target: self.target,
+            orthographic: angle.orthographic,
+        }
+    }
+}
+
+// Camera overrides sent with the request, applied to every view. Angles are in radians and
+// replace the framing's own; zoom divides its distance. Out of range values are clamped.
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+struct CameraAngle {
+    yaw: Option<f32>,
+    pitch: Option<f32>,
+    zoom: Option<f32>,
+    // Quarter turns around the avatar from the `view` param, added on top of the yaw.
+    turn: f32,
+    // No perspective, so parts further from the camera aren't drawn smaller.
+    orthographic: bool,
+}
+
+impl CameraAngle {
+    // Short of straight up or down, where the camera's up vector stops making sense.
+    const MAX_PITCH: f32 = 1.5;
+    const ZOOM_RANGE: (f32, f32) = (0.25, 4.0);
+
+    // Err names the field that isn't a number, or view or projection when it isn't one of the
+    // values they take.
+    fn parse(params: &RenderParams) -> Result<CameraAngle, &'static str> {
+        let parse = |name: &'static str, value: &Option<String>| match value.as_deref() {
+            None => Ok(None),
+            Some(value) => match value.parse::<f32>() {
+                Ok(v) if v.is_finite() => Ok(Some(v)),
+                _ => Err(name),
+            },
+        };
+
+        let (min_zoom, max_zoom) = Self::ZOOM_RANGE;
+        Ok(CameraAngle {
+            yaw: parse("yaw", &params.yaw)?.map(|yaw| yaw.rem_euclid(std::f32::consts::TAU)),
+            pitch: parse("pitch", &params.pitch)?
+                .map(|pitch| pitch.clamp(-Self::MAX_PITCH, Self::MAX_PITCH)),
+            zoom: parse("zoom", &params.zoom)?.map(|zoom| zoom.clamp(min_zoom, max_zoom)),
+            // Whole quarter turns from the default three-quarter angle (front, a little to the
+            // avatar's right), so `front` is the usual render and `left` mostly shows its left.
+            turn: match params.view.as_deref() {
+                None | Some("front") => 0.0,
+                Some("left") => std::f32::consts::FRAC_PI_2,
+                Some("back") => std::f32::consts::PI,
+                Some("right") => -std::f32::consts::FRAC_PI_2,
+                Some(_) => return Err("view"),
+            },
+            orthographic: match params.projection.as_deref() {
+                None | Some("perspective") => false,
+                Some("orthographic") => true,
+                Some(_) => return Err("projection"),
+            },
+        })
+    }
+}
+
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+struct OutputOptions {
+    both_views: bool,
+    lqip: bool,
+    mirror: bool,
+    // Composited under the render after capture. None keeps the background transparent.
+    background: Option<u32>,
+    pass: RenderPass,
+    // Set when the client asked for embed_metadata.
+    metadata: Option<MetadataSubject>,
+    timings: bool,
+    // Edge length of the returned image. None keeps the framebuffer's RENDER_SIZE.
+    size: Option<u32>,
+    format: OutputFormat,
+    camera: CameraAngle,
+    // Crop each view to the drawn pixels. Results carry the crops as JSON under "crop".
+    trim: bool,
+}
+
+// What the render is of, written into the PNG alongside the item list and timestamp.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+struct MetadataSubject {
+    id: i32,
+    job_type: i32,
+}
+
+impl MetadataSubject {
+    fn text_chunks(&self, item_ids: &[i32]) -> Vec<(String, String)> {
+        let items: Vec<String> = item_ids.iter().map(i32::to_string).collect();
+        vec![
+            ("Software".into(), PROGRAM_NAME.into()),
+            ("Creation Time".into(), Utc::now().to_rfc3339()),
+            ("lsd:id".into(), self.id.to_string()),
+            ("lsd:job_type".into(), self.job_type.to_string()),
+            ("lsd:items".into(), items.join(",")),
+        ]
+    }
+}
+
+// Render-loop state every capture needs, set up once at startup.
+struct CaptureSettings {
+    clear_color: u32,
+    materials: PassMaterials,
+    // Multisampled offscreen target SUPERSAMPLE times the size of the window, None to draw to
+    // the window where the backend can't resolve one.
+    target: Option<RenderTarget>,
+}
+
+impl OutputOptions {
+    fn is_json(&self) -> bool {
+        self.both_views || self.lqip || self.timings
+    }
+}
+
+// Load time per accessory type for one scene, to see where caching would pay off.
+#[derive(Default)]
+struct AccessoryTimings {
+    by_type: BTreeMap<&'static str, (u32, Duration)>,
+}
+
+impl AccessoryTimings {
+    fn record(&mut self, item_type: i8, elapsed: Duration) {
+        let name = match item_type {
+            9 => "hat",
+            10 => "shoulder",
+            11 => "back",
+            12 => "gear",
+            8 => "head_swap",
+            7 => "face",
+            6 => "pants",
+            5 => "shirt",
+            4 => "tshirt",
+            _ => "other",
+        };
+        let entry = self.by_type.entry(name).or_default();
+        entry.0 += 1;
+        entry.1 += elapsed;
+    }
+
+    fn log(&self) {
+        for (name, (count, total)) in &self.by_type {
+            debug!(
+                "Accessory timing {}: {} in {:.2}ms",
+                name,
+                count,
+                total.as_secs_f64() * 1000.0
+            );
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let map = self
+            .by_type
+            .iter()
+            .map(|(name, (count, total))| {
+                let ms = total.as_secs_f64() * 1000.0;
+                (
+                    name.to_string(),
+                    serde_json::json!({ "count": count, "total_ms": ms, "avg_ms": ms / *count as f64 }),
+                )
+            })
+            .collect();
+        serde_json::Value::Object(map)
+    }
+}
+
+// Where a trimmed view sat in the full frame, in output pixels.
+#[derive(Clone, Copy, Serialize)]
+struct Crop {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+// One captured view, encoded in the format OutputOptions asked for.
+struct RenderedView {
+    image: Vec<u8>,
+    // Set when the view was trimmed.
+    crop: Option<Crop>,
+}
+
+// Everything Renderer::render made, before the server turns it into base64 or JSON.
+struct Render {
+    // Seen from the framing that was asked for.
+    main: RenderedView,
+    // Added by both_views.
+    headshot: Option<RenderedView>,
+    // PNG, whatever the format of the views.
+    lqip: Option<Vec<u8>>,
+    timings: AccessoryTimings,
+    // Accessories that loaded and went into the scene.
+    accessories_drawn: usize,
+}
+
+// The renderer without the queue or HTTP around it: scene setup, draw calls, readback and
+// encoding. It holds the meshes and GPU state set up once at startup, so it has to be created
+// and used on the thread that owns the macroquad context.
+struct Renderer {
+    static_meshes: StaticMeshes,
+    assets: AssetPaths,
+    color_textures: ColorTextureCache,
+    capture_settings: CaptureSettings,
+    // Vertices and indices macroquad's draw call buffers hold, see fit_drawcall_buffers.
+    drawcall_capacity: (usize, usize),
+}
+
+impl Renderer {
+    fn new() -> Renderer {
+        let static_meshes = StaticMeshes {
+            head: load_static_mesh_from_bytes("default", DEFAULT_MESH_BYTES),
+            rarm: load_static_mesh_from_bytes("rightarm", RARM_MESH_BYTES),
+            larm: load_static_mesh_from_bytes("leftarm", LARM_MESH_BYTES),
+            rleg: load_static_mesh_from_bytes("rightleg", RLEG_MESH_BYTES),
+            lleg: load_static_mesh_from_bytes("leftleg", LLEG_MESH_BYTES),
+            trso: load_static_mesh_from_bytes("torso", TRSO_MESH_BYTES),
+            tshirt: load_static_mesh_from_bytes("tshirt", TSHIRT_MESH_BYTES),
+            placeholder: load_static_mesh_from_bytes("placeholder", PLACEHOLDER_MESH_BYTES),
+        };
+
+        Renderer {
+            static_meshes,
+            assets: AssetPaths::from_env(),
+            color_textures: ColorTextureCache::from_env(),
+            capture_settings: CaptureSettings {
+                clear_color: match env::var("CLEAR_COLOR") {
+                    Ok(value) if !value.is_empty() => parse_hex_color(&value)
+                        .expect("CLEAR_COLOR must be a hex color like 808080"),
+                    _ => 0x000000,
+                },
+                materials: PassMaterials::load(),
+                target: offscreen_target(),
+            },
+            drawcall_capacity: DEFAULT_DRAWCALL_CAPACITY,
+        }
+    }
+
+    // A mesh has to fit a single draw call, so the buffers grow to the biggest chunk drawn so
+    // far (rounded up, so they aren't reallocated for every slightly bigger mesh). Every draw
+    // call gets buffers this size, which is why they don't start at the largest chunk
+    // process_mesh can make. Growing drops pending draws, so it happens before drawing a scene.
+    fn fit_drawcall_buffers(&mut self, scene: &[macroquad::models::Mesh]) {
+        let vertices = scene.iter().map(|m| m.vertices.len()).max().unwrap_or(0) + 1;
+        let indices = scene.iter().map(|m| m.indices.len()).max().unwrap_or(0) + 1;
+        let (max_vertices, max_indices) = self.drawcall_capacity;
+        if vertices <= max_vertices && indices <= max_indices {
+            return;
+        }
+
+        let grow = |needed: usize, current: usize, largest: usize| {
+            needed.next_power_of_two().min(largest).max(current)
+        };
+        self.drawcall_capacity = (
+            grow(vertices, max_vertices, MESH_CHUNK_VERTICES + 1),
+            grow(indices, max_indices, MESH_CHUNK_INDICES + 1),
+        );
+        debug!(
+            "Draw call buffers grown to {} vertices and {} indices",
+            self.drawcall_capacity.0, self.drawcall_capacity.1
+        );
+        gl_set_drawcall_buffer_capacity(self.drawcall_capacity.0, self.drawcall_capacity.1);
+    }
+
+    // Loads the scene once and captures it from `framing`, plus a headshot with both_views.
+    fn render(
+        &mut self,
+        accessories: Vec<ItemAsset>,
+        colors: HexBodyColors,
+        options: &SceneOptions,
+        output: &OutputOptions,
+        framing: &CameraFraming,
+    ) -> Result<Render, ServerError> {
+        let text = match &output.metadata {
+            Some(subject) => {
+                subject.text_chunks(&accessories.iter().map(|a| a.id).collect::<Vec<_>>())
+            }
+            None => Vec::new(),
+        };
+
+        let mut timings = AccessoryTimings::default();
+        let (scene, accessories_drawn) = build_scene(
+            accessories,
+            colors,
+            options,
+            &self.static_meshes,
+            &self.assets,
+            &mut self.color_textures,
+            &mut timings,
+        );
+        timings.log();
+        self.fit_drawcall_buffers(&scene);
+
+        let view =
+            |framing: &CameraFraming| -> Result<(image::RgbaImage, Option<Crop>), ServerError> {
+                let image = capture_view(&scene, framing, output, &self.capture_settings)?;
+                Ok(if output.trim {
+                    let (image, crop) = trim_view(image);
+                    (image, Some(crop))
+                } else {
+                    (image, None)
+                })
+            };
+
+        let (image, crop) = view(framing)?;
+        let lqip = if output.lqip {
+            Some(make_lqip(&image)?)
+        } else {
+            None
+        };
+        let main = RenderedView {
+            image: encode_image(&image, output.format, &text)?,
+            crop,
+        };
+
+        let headshot = if output.both_views {
+            // The headshot reuses the already loaded scene, only the camera moves.
+            let (image, crop) = view(&CameraFraming::HEADSHOT)?;
+            Some(RenderedView {
+                image: encode_image(&image, output.format, &text)?,
+                crop,
+            })
+        } else {
+            None
+        };
+
+        Ok(Render {
+            main,
+            headshot,
+            lqip,
+            timings,
+            accessories_drawn,
+        })
+    }
+}
+
+// Mirroring and the background are post-processes, so every view (and the LQIP) gets them.
+fn capture_view(
+    scene: &[macroquad::models::Mesh],
+    framing: &CameraFraming,
+    output: &OutputOptions,
+    settings: &CaptureSettings,
+) -> Result<image::RgbaImage, ServerError> {
+    // A solid background on a color render is drawn straight into the framebuffer, so
+    // edges blend against it on the GPU. Data passes get it composited afterwards.
+    let direct_background = output
+        .background
+        .filter(|_| output.pass == RenderPass::Color);
+    let mut image = capture_scene(
+        scene,
+        &framing.adjusted(&output.camera),
+        output.pass,
+        settings,
+        direct_background,
+    )
+    .map_err(|e| ServerError::RenderFailed(format!("capture: {}", e)))?;
+    // Supersampled captures come back bigger than RENDER_SIZE; one resample takes them
+    // straight to the size that was asked for.
+    let size = output.size.unwrap_or(RENDER_SIZE as u32);
+    if image.dimensions() != (size, size) {
+        image = image::imageops::resize(&image, size, size, FilterType::Lanczos3);
+    }
+    if output.mirror {
+        image = image::imageops::flip_horizontal(&image);
+    }
+    if let Some(background) = output.background
+        && direct_background.is_none()
+    {
+        let (width, height) = image.dimensions();
+        let bytes = replace_transparent_with_color(image.into_raw(), background, 0);
+        image = image::RgbaImage::from_raw(width, height, bytes).ok_or_else(|| {
+            ServerError::RenderFailed("background fill changed the image size".into())
+        })?;
+    }
+    Ok(image)
+}
+
+// Cuts a view down to its non-transparent pixels plus TRIM_PADDING, and says where the kept
+// part sat in the full frame. Renders with a background are opaque and stay whole.
+fn trim_view(image: image::RgbaImage) -> (image::RgbaImage, Crop) {
+    let (width, height) = image.dimensions();
+    let (x, y, width, height) =
+        opaque_bounds(&image, TRIM_PADDING).unwrap_or((0, 0, width, height));
+    (
+        image::imageops::crop_imm(&image, x, y, width, height).to_image(),
+        Crop {
+            x,
+            y,
+            width,
+            height,
+        },
+    )
+}
+
+// Text chunks are PNG only, JPEGs go out without them.
+fn encode_image(
+    image: &image::RgbaImage,
+    format: OutputFormat,
+    text: &[(String, String)],
+) -> Result<Vec<u8>, ServerError> {
+    match format {
+        OutputFormat::Png => encode_png(image, text),
+        OutputFormat::Jpeg { quality } => encode_jpeg(image, quality),
+    }
+}
+
+// Runs a job through the renderer and packs the result for the response: the base64 image, or
+// a JSON object when extra outputs were asked for. `framing` is the main view; both_views adds
+// a headshot next to it.
 fn render_scene(
+    renderer: &mut Renderer,
+    accessories: Vec<ItemAsset>,
+    colors: HexBodyColors,
+    options: &SceneOptions,
+    output: &OutputOptions,
+    framing: &CameraFraming,
+) -> RenderAnswer {
+    info!("STARTED RENDER");
+
+    let render = renderer.render(accessories, colors, options, output, framing)?;
+    let accessories_drawn = render.accessories_drawn;
+    let base64 = |bytes: &[u8]| base64::engine::general_purpose::STANDARD.encode(bytes);
+
+    let image_b64 = base64(&render.main.image);
+    // Trimmed renders stay JSON even when the client didn't ask for it, render_response
+    // takes the crop back out.
+    if !output.is_json() && !output.trim {
+        return Ok(RenderOutput {
+            result: image_b64,
+            accessories_drawn,
+        });
+    }
+
+    let mut response = serde_json::Map::new();
+    let mut crops = serde_json::Map::new();
+    let main_key = match render.headshot {
+        Some(headshot) => {
+            response.insert("headshot".into(), base64(&headshot.image).into());
+            if let Some(crop) = headshot.crop {
+                crops.insert("headshot".into(), serde_json::json!(crop));
+            }
+            "fullbody"
+        }
+        None => "image",
+    };
+    response.insert(main_key.into(), image_b64.into());
+    if let Some(crop) = render.main.crop {
+        crops.insert(main_key.into(), serde_json::json!(crop));
+    }
+    if let Some(lqip) = render.lqip {
+        response.insert("lqip".into(), base64(&lqip).into());
+    }
+    if output.timings {
+        response.insert("timings".into(), render.timings.to_json());
+    }
+    if output.trim {
+        response.insert("crop".into(), crops.into());
+    }
+
+    Ok(RenderOutput {
+        result: serde_json::Value::Object(response).to_string(),
+        accessories_drawn,
+    })
+}
+
+// The head mesh to draw and the color behind its face: the default head in the head color, or
+// a head swap in its own material color (white without one).
+struct Head {
+    meshes: Vec<tobj::Mesh>,
+    face_fill: u32,
+    swapped: bool,
+}
+
+impl Head {
+    fn default(meshes: &[tobj::Mesh], color: u32) -> Head {
+        Head {
+            meshes: meshes.to_vec(),
+            face_fill: color,
+            swapped: false,
+        }
+    }
+
+    // Puts on the head swap at `location`. If it can't be loaded the default head comes back
+    // (even over an earlier swap), so the face still has the UVs it was made for.
+    fn swap(
+        &mut self,
+        assets: &AssetPaths,
+        location: &str,
+        default: &[tobj::Mesh],
+        color: u32,
+    ) -> bool {
+        let loaded = assets
+            .resolve(location)
+            .ok()
+            .and_then(|path| load_static_mesh(&path));
+        *self = match loaded {
+            Some((meshes, material_color)) => Head {
+                meshes,
+                face_fill: material_color.unwrap_or(0xFFFFFF),
+                swapped: true,
+            },
+            None => Head::default(default, color),
+        };
+        self.swapped
+    }
+}
+
+// Loads and textures everything once so the same scene can be captured from several framings.
+// Also returns how many accessories made it in; placeholders for broken ones don't count.
+fn build_scene(
     accessories: Vec<ItemAsset>,
     colors: HexBodyColors,
+    options: &SceneOptions,
     static_meshes: &StaticMeshes,
-) -> String {
-    let now: DateTime<Utc> = Utc::now();
-    println!("[{}] STARTED RENDER", now.format("%d-%m-%Y %H:%M:%S"));
-
-    let yaw: f32 = 1.0;
-    let pitch: f32 = 0.4;
-    let radius: f32 = 10.0;
-    let target = vec3(-0.25, -1.75, -1.0);
-    let world_up = vec3(0.0, 1.0, 0.0);
-
-    let new_pos = vec3(
-        radius * yaw.cos() * pitch.cos(),
-        radius * pitch.sin(),
-        radius * yaw.sin() * pitch.cos(),
-    ) + target;
-
-    clear_background(Color::with_alpha(&Color::from_hex(0x000000), 0.0));
-
-    set_camera(&Camera3D {
-        position: new_pos,
-        up: world_up,
-        target,
-        ..Default::default()
-    });
+    assets: &AssetPaths,
+    color_textures: &mut ColorTextureCache,
+    timings: &mut AccessoryTimings,
+) -> (Vec<macroquad::models::Mesh>, usize) {
+    let mut scene = Vec::new();
+    let mut drawn = 0;
 
     let face_loc = std::path::Path::new("src/face.png");
-    let mut face_texture = match process_img(face_loc) {
-        Ok((w, h, bytes)) => Texture2D::from_rgba8(
-            w as u16,
-            h as u16,
-            &replace_transparent_with_color(bytes, colors.head),
-        ),
-        Err(e) => {
-            eprintln!("Default face couldn't be loaded: {}", e);
-            Texture2D::from_rgba8(1, 1, &[255, 0, 0, 255])
+    let mut face_image = if options.show_face {
+        match process_img(face_loc) {
+            Ok(image) => Some(image),
+            Err(e) => {
+                error!("Default face couldn't be loaded: {}", e);
+                None
+            }
         }
+    } else {
+        None
     };
+    let mut head = Head::default(&static_meshes.head, colors.head);
+    // A head swap with its own texture wears that instead of the composited face.
+    let mut head_texture: Option<Texture2D> = None;
 
-    let mut head_mesh_data: Option<tobj::Mesh> = static_meshes.head.clone();
-    let rarm_mesh_data: Option<tobj::Mesh> = static_meshes.rarm.clone();
-    let larm_mesh_data: Option<tobj::Mesh> = static_meshes.larm.clone();
-    let rleg_mesh_data: Option<tobj::Mesh> = static_meshes.rleg.clone();
-    let lleg_mesh_data: Option<tobj::Mesh> = static_meshes.lleg.clone();
-    let trso_mesh_data: Option<tobj::Mesh> = static_meshes.trso.clone();
+    let rarm_mesh_data: Vec<tobj::Mesh> = static_meshes.rarm.clone();
+    let larm_mesh_data: Vec<tobj::Mesh> = static_meshes.larm.clone();
+    let rleg_mesh_data: Vec<tobj::Mesh> = static_meshes.rleg.clone();
+    let lleg_mesh_data: Vec<tobj::Mesh> = static_meshes.lleg.clone();
+    let trso_mesh_data: Vec<tobj::Mesh> = static_meshes.trso.clone();
 
-    let mut rarm_texture = Texture2D::from_rgba8(1, 1, &from_hex(colors.rarm));
-    let mut larm_texture = Texture2D::from_rgba8(1, 1, &from_hex(colors.larm));
-    let mut rleg_texture = Texture2D::from_rgba8(1, 1, &from_hex(colors.rleg));
-    let mut lleg_texture = Texture2D::from_rgba8(1, 1, &from_hex(colors.lleg));
-    let mut trso_texture = Texture2D::from_rgba8(1, 1, &from_hex(colors.trso));
+    let mut rarm_texture = color_textures.texture(colors.rarm);
+    let mut larm_texture = color_textures.texture(colors.larm);
+    let mut rleg_texture = color_textures.texture(colors.rleg);
+    let mut lleg_texture = color_textures.texture(colors.lleg);
+    let mut trso_texture = color_textures.texture(colors.trso);
 
     let mut tshirt_meshes = Vec::new();
+    let fill_clothing = |bytes: Vec<u8>, color: u32| {
+        if options.keep_clothing_alpha {
+            bytes
+        } else {
+            replace_transparent_with_color(bytes, color, options.alpha_threshold)
+        }
+    };
+
+    let max_accessories = MAX_ACCESSORIES
+        .get()
+        .copied()
+        .unwrap_or(DEFAULT_MAX_ACCESSORIES);
+    if accessories.len() > max_accessories {
+        warn!(
+            "Skipping {} of {} accessories, past MAX_ACCESSORIES ({})",
+            accessories.len() - max_accessories,
+            accessories.len(),
+            max_accessories
+        );
+    }
 
-    for accessory in accessories {
+    for accessory in accessories.into_iter().take(max_accessories) {
         let loc = accessory.location.clone().unwrap_or_default();
-        if loc.is_empty() {
+        if loc.is_empty() && accessory.data.is_none() {
             continue;
         }
+        let started = Instant::now();
 
         match accessory.item_type {
-            9 => {
-                // HAT
-                let tex_path = accessory.texture_path.clone().unwrap_or_default();
-                if let Ok(m) = load_resources_and_mesh(&loc, &tex_path) {
-                    draw_mesh(&m);
+            9..=12 => {
+                // HAT, SHOULDER, BACK, GEAR
+                // NULL and empty both mean the item has no texture.
+                let tex_path = accessory.texture_path.as_deref().filter(|t| !t.is_empty());
+                let placement = attachment_transform(accessory.item_type, options);
+                // Hats are sized for the head they sit on.
+                let body_scale = if accessory.item_type == 9 {
+                    options.proportions.head_scale()
+                } else {
+                    Vec3::ONE
+                };
+                match load_resources_and_mesh(assets, &loc, tex_path) {
+                    Ok(mut m) => {
+                        let scale = match accessory.scale {
+                            None => 1.0,
+                            Some(scale) if valid_scale(scale) => scale,
+                            Some(scale) => {
+                                warn!(
+                                    "Item {} has invalid scale {}, ignoring it",
+                                    accessory.id, scale
+                                );
+                                1.0
+                            }
+                        };
+                        for chunk in &mut m {
+                            scale_mesh(chunk, Vec3::splat(scale) * body_scale);
+                            transform_mesh(chunk, placement);
+                        }
+                        scene.extend(m);
+                        drawn += 1;
+                    }
+                    // The rest of the avatar still renders, with a checkered cube where the
+                    // item should be so the broken asset stands out.
+                    Err(e) => {
+                        error!(
+                            "Item {} ({}) couldn't be loaded, drawing a placeholder: {}",
+                            accessory.id, loc, e
+                        );
+                        let texture = checker_texture();
+                        let placement = if accessory.item_type == 9 {
+                            Mat4::from_translation(PLACEHOLDER_HAT_POSITION * body_scale)
+                        } else {
+                            placement
+                        };
+                        for mesh in &static_meshes.placeholder {
+                            for mut chunk in process_mesh(mesh, &texture) {
+                                transform_mesh(&mut chunk, placement);
+                                scene.push(chunk);
+                            }
+                        }
+                    }
                 }
             }
             8 => {
                 // HEAD SWAP
-                let mesh_full_path = format!("{}/{}", BASE_HTTP_PATH, loc);
-                if let Some(new_mesh) = load_static_mesh(&mesh_full_path) {
-                    head_mesh_data = Some(new_mesh);
+                head_texture = None;
+                if head.swap(assets, &loc, &static_meshes.head, colors.head) {
+                    drawn += 1;
+
+                    let tex_path = accessory.texture_path.clone().unwrap_or_default();
+                    if !tex_path.is_empty() {
+                        match assets.load_image(&tex_path) {
+                            Ok((w, h, bytes)) => {
+                                head_texture =
+                                    Some(Texture2D::from_rgba8(w as u16, h as u16, &bytes));
+                            }
+                            Err(e) => {
+                                warn!("Head swap texture {} couldn't be loaded: {}", tex_path, e)
+                            }
+                        }
+                    }
+                } else {
+                    warn!(
+                        "Head swap {} ({}) couldn't be loaded, using the default head",
+                        accessory.id, loc
+                    );
                 }
             }
             7 => {
                 // FACE TEXTURE
-                let tmp_path = format!("{}/{}", BASE_HTTP_PATH, loc);
-                if let Ok((w, h, bytes)) = process_img(std::path::Path::new(&tmp_path)) {
-                    face_texture = Texture2D::from_rgba8(
-                        w as u16,
-                        h as u16,
-                        &replace_transparent_with_color(bytes, colors.head),
-                    );
+                if options.show_face
+                    && let Ok(image) = item_image(&accessory, assets)
+                {
+                    face_image = Some(image);
+                    drawn += 1;
                 }
             }
             6 => {
                 // PANTS
-                let tmp_path = format!("{}/{}", BASE_HTTP_PATH, loc);
-                if let Ok((w, h, bytes)) = process_img(std::path::Path::new(&tmp_path)) {
-                    rleg_texture = Texture2D::from_rgba8(
-                        w as u16,
-                        h as u16,
-                        &replace_transparent_with_color(bytes.clone(), colors.rleg),
-                    );
-                    lleg_texture = Texture2D::from_rgba8(
-                        w as u16,
-                        h as u16,
-                        &replace_transparent_with_color(bytes, colors.lleg),
+                if let Ok((w, h, bytes)) = item_image(&accessory, assets) {
+                    rleg_texture = tiled_clothing_texture(
+                        w,
+                        h,
+                        &fill_clothing(bytes.clone(), colors.rleg),
+                        options,
                     );
+                    lleg_texture =
+                        tiled_clothing_texture(w, h, &fill_clothing(bytes, colors.lleg), options);
+                    drawn += 1;
                 }
             }
             5 => {
                 // SHIRT
-                let tmp_path = format!("{}/{}", BASE_HTTP_PATH, loc);
-                if let Ok((w, h, bytes)) = process_img(std::path::Path::new(&tmp_path)) {
-                    trso_texture = Texture2D::from_rgba8(
-                        w as u16,
-                        h as u16,
-                        &replace_transparent_with_color(bytes.clone(), colors.trso),
+                if let Ok((w, h, bytes)) = item_image(&accessory, assets) {
+                    trso_texture = tiled_clothing_texture(
+                        w,
+                        h,
+                        &fill_clothing(bytes.clone(), colors.trso),
+                        options,
                     );
-                    rarm_texture = Texture2D::from_rgba8(
-                        w as u16,
-                        h as u16,
-                        &replace_transparent_with_color(bytes.clone(), colors.rarm),
-                    );
-                    larm_texture = Texture2D::from_rgba8(
-                        w as u16,
-                        h as u16,
-                        &replace_transparent_with_color(bytes, colors.larm),
+                    rarm_texture = tiled_clothing_texture(
+                        w,
+                        h,
+                        &fill_clothing(bytes.clone(), colors.rarm),
+                        options,
                     );
+                    larm_texture =
+                        tiled_clothing_texture(w, h, &fill_clothing(bytes, colors.larm), options);
+                    drawn += 1;
                 }
             }
             4 => {
                 // T-SHIRT
-                let tmp_path = format!("{}/{}", BASE_HTTP_PATH, loc);
-                if let Ok((w, h, bytes)) = process_img(std::path::Path::new(&tmp_path)) {
-                    if let Some(tshirt_mesh) = static_meshes.tshirt.clone() {
-                        let texture = Texture2D::from_rgba8(w as u16, h as u16, &bytes);
-                        let final_mesh = process_mesh(&tshirt_mesh, &texture);
-                        tshirt_meshes.push(final_mesh);
+                if let Ok((w, h, bytes)) = item_image(&accessory, assets) {
+                    let texture = clothing_texture(w, h, &bytes, options.texture_filter);
+                    for tshirt_mesh in &static_meshes.tshirt {
+                        for mut chunk in process_mesh(tshirt_mesh, &texture) {
+                            scale_mesh(&mut chunk, options.proportions.torso_scale());
+                            tshirt_meshes.push(chunk);
+                        }
                     }
+                    drawn += 1;
                 }
             }
             _ => {
-                eprintln!("Item Type {} not implemented.", accessory.item_type)
+                warn!("Item Type {} not implemented.", accessory.item_type)
             }
         }
+        timings.record(accessory.item_type, started.elapsed());
     }
 
-    gl_use_default_material();
+    let fill_face = match options.face_transparency {
+        FaceTransparency::Fill => true,
+        FaceTransparency::Keep => false,
+        FaceTransparency::Auto => !head.swapped,
+    };
+    let face_texture = match (head_texture, face_image) {
+        (Some(texture), _) => texture,
+        (None, Some((w, h, bytes))) if fill_face => Texture2D::from_rgba8(
+            w as u16,
+            h as u16,
+            &replace_transparent_with_color(bytes, head.face_fill, options.alpha_threshold),
+        ),
+        (None, Some((w, h, bytes))) => Texture2D::from_rgba8(w as u16, h as u16, &bytes),
+        (None, None) if !options.show_face => color_textures.texture(head.face_fill),
+        (None, None) => Texture2D::from_rgba8(1, 1, &[255, 0, 0, 255]),
+    };
 
-    if let Some(mesh) = trso_mesh_data {
-        draw_mesh(&process_mesh(&mesh, &trso_texture));
+    // Body parts are modeled around the neck, so the proportions scale them in place before
+    // the pose moves them.
+    let proportions = &options.proportions;
+    for mesh in &trso_mesh_data {
+        for mut chunk in process_mesh(mesh, &trso_texture) {
+            scale_uvs(&mut chunk, options.tiling);
+            scale_mesh(&mut chunk, proportions.torso_scale());
+            scene.push(chunk);
+        }
+    }
+    for mesh in &rarm_mesh_data {
+        for mut chunk in process_mesh(mesh, &rarm_texture) {
+            scale_uvs(&mut chunk, options.tiling);
+            scale_mesh(&mut chunk, proportions.limb_scale());
+            transform_mesh(&mut chunk, options.pose.rarm_transform());
+            scene.push(chunk);
+        }
     }
-    if let Some(mesh) = rarm_mesh_data {
-        draw_mesh(&process_mesh(&mesh, &rarm_texture));
+    for mesh in &larm_mesh_data {
+        for mut chunk in process_mesh(mesh, &larm_texture) {
+            scale_uvs(&mut chunk, options.tiling);
+            scale_mesh(&mut chunk, proportions.limb_scale());
+            transform_mesh(&mut chunk, options.pose.larm_transform());
+            scene.push(chunk);
+        }
+    }
+    for mesh in &head.meshes {
+        for mut chunk in process_mesh(mesh, &face_texture) {
+            scale_mesh(&mut chunk, proportions.head_scale());
+            scene.push(chunk);
+        }
+    }
+    for mesh in &lleg_mesh_data {
+        for mut chunk in process_mesh(mesh, &lleg_texture) {
+            scale_uvs(&mut chunk, options.tiling);
+            scale_mesh(&mut chunk, proportions.limb_scale());
+            transform_mesh(
+                &mut chunk,
+                proportions.leg_transform(options.pose.lleg_transform()),
+            );
+            scene.push(chunk);
+        }
+    }
+    for mesh in &rleg_mesh_data {
+        for mut chunk in process_mesh(mesh, &rleg_texture) {
+            scale_uvs(&mut chunk, options.tiling);
+            scale_mesh(&mut chunk, proportions.limb_scale());
+            transform_mesh(
+                &mut chunk,
+                proportions.leg_transform(options.pose.rleg_transform()),
+            );
+            scene.push(chunk);
+        }
     }
-    if let Some(mesh) = larm_mesh_data {
-        draw_mesh(&process_mesh(&mesh, &larm_texture));
+    scene.extend(tshirt_meshes);
+
+    (scene, drawn)
+}
+
+// Attachment points in the space of the bundled body OBJs, where rigid accessories put their
+// origin. Hats have none: they're authored in the same space as the body, fitted around the
+// default head (neck at the origin, top at y = 1.09), so any offset would lift every existing hat
+// off the head. They're only scaled along with it.
+// Top of the right arm.
+const SHOULDER_ATTACHMENT: Vec3 = Vec3::new(1.5, 0.0, 0.0);
+// Middle of the torso's back face (the front faces +Z).
+const BACK_ATTACHMENT: Vec3 = Vec3::new(0.0, -1.0, -0.5);
+// Bottom of the right arm, where the hand is.
+const GEAR_ATTACHMENT: Vec3 = Vec3::new(1.5, -2.0, 0.0);
+// Gear is modeled upright with its grip at the origin; a quarter turn about X tips it forward
+// (+Y to +Z) so it sticks out of the hanging hand.
+const GEAR_ROTATION: Quat = Quat::from_xyzw(
+    std::f32::consts::FRAC_1_SQRT_2,
+    0.0,
+    0.0,
+    std::f32::consts::FRAC_1_SQRT_2,
+);
+// The neck would hide the placeholder cube inside the head, so a broken hat's sits on top of it.
+const PLACEHOLDER_HAT_POSITION: Vec3 = Vec3::new(0.0, 1.6, 0.0);
+
+// Moves a rigid accessory from its own space onto the body. Gear is held, so it also swings
+// with the right arm when posed. The attachment points follow the body's proportions.
+fn attachment_transform(item_type: i8, options: &SceneOptions) -> Mat4 {
+    let proportions = &options.proportions;
+    match item_type {
+        10 => Mat4::from_translation(SHOULDER_ATTACHMENT),
+        11 => Mat4::from_translation(BACK_ATTACHMENT * proportions.torso_scale()),
+        12 => {
+            options.pose.rarm_transform()
+                * Mat4::from_rotation_translation(
+                    GEAR_ROTATION,
+                    GEAR_ATTACHMENT * proportions.limb_scale(),
+                )
+        }
+        _ => Mat4::IDENTITY,
     }
-    if let Some(mesh) = head_mesh_data {
-        draw_mesh(&process_mesh(&mesh, &face_texture));
+}
+
+// Image for a t-shirt, shirt, pants or face: inline data from an inline spec, else the file.
+fn item_image(
+    accessory: &ItemAsset,
+    assets: &AssetPaths,
+) -> Result<(u32, u32, Vec<u8>), Box<dyn std::error::Error>> {
+    if let Some(data) = &accessory.data {
+        let bytes = base64::engine::general_purpose::STANDARD.decode(data)?;
+        let image = image::load_from_memory(&bytes)?.to_rgba8();
+        return Ok((image.width(), image.height(), image.into_raw()));
     }
-    if let Some(mesh) = lleg_mesh_data {
-        draw_mesh(&process_mesh(&mesh, &lleg_texture));
+
+    assets.load_image(accessory.location.as_deref().unwrap_or_default())
+}
+
+fn clothing_texture(w: u32, h: u32, bytes: &[u8], filter: TextureFilter) -> Texture2D {
+    let texture = Texture2D::from_rgba8(w as u16, h as u16, bytes);
+    texture.set_filter(filter.into());
+    texture
+}
+
+// Shirts and pants go on the body meshes, whose UVs get scaled by the tiling factor, so they
+// need to repeat rather than clamp at the edge.
+fn tiled_clothing_texture(w: u32, h: u32, bytes: &[u8], options: &SceneOptions) -> Texture2D {
+    let texture = clothing_texture(w, h, bytes, options.texture_filter);
+    if options.tiling != 1.0 {
+        // Safe: only touches this texture's sampler state, on the render thread.
+        let gl = unsafe { get_internal_gl() };
+        gl.quad_context.texture_set_wrap(
+            texture.raw_miniquad_id(),
+            TextureWrap::Repeat,
+            TextureWrap::Repeat,
+        );
     }
-    if let Some(mesh) = rleg_mesh_data {
-        draw_mesh(&process_mesh(&mesh, &rleg_texture));
+    texture
+}
+
+// Without a background the clear color never shows (its alpha is zero), but partially
+// transparent edges blend against it, so it should be close to whatever the image ends up
+// composited over. With one, the framebuffer is cleared to it and the result is opaque.
+fn capture_scene(
+    scene: &[macroquad::models::Mesh],
+    framing: &CameraFraming,
+    pass: RenderPass,
+    settings: &CaptureSettings,
+    background: Option<u32>,
+) -> Result<image::RgbaImage, String> {
+    let mut mismatch = String::new();
+    let mut captured = None;
+    // Some GPUs and headless setups hand back a buffer that doesn't match the size they
+    // report. A second draw and readback is usually fine, so that gets one retry.
+    for attempt in 1..=CAPTURE_ATTEMPTS {
+        // The retry shouldn't start until the GPU is done with the failed attempt, or it can
+        // read back the same half finished buffer.
+        if attempt > 1 {
+            unsafe {
+                get_internal_gl().flush();
+                macroquad::miniquad::gl::glFinish();
+            }
+        }
+        // The camera picks the render target, so it has to be set before clearing.
+        set_camera(&Camera3D {
+            render_target: settings.target.clone(),
+            ..framing.camera()
+        });
+        match background {
+            Some(background) => clear_background(Color::from_hex(background)),
+            None => clear_background(Color::with_alpha(
+                &Color::from_hex(settings.clear_color),
+                0.0,
+            )),
+        }
+        settings.materials.apply(pass, framing.radius);
+
+        for mesh in scene {
+            draw_mesh(mesh);
+        }
+
+        let img_data = match &settings.target {
+            Some(target) => {
+                // Reading the texture doesn't flush pending draws the way get_screen_data does.
+                unsafe { get_internal_gl() }.flush();
+                target.texture.get_texture_data()
+            }
+            None => get_screen_data(),
+        };
+        gl_use_default_material();
+        let width = img_data.width as u32;
+        let height = img_data.height as u32;
+        let len = img_data.bytes.len();
+
+        if let Some(image) = image::RgbaImage::from_raw(width, height, img_data.bytes) {
+            captured = Some(image);
+            break;
+        }
+        mismatch = format!(
+            "screen data is {} bytes, expected {}x{}x4 = {}",
+            len,
+            width,
+            height,
+            width as usize * height as usize * 4
+        );
+        warn!("Readback attempt {} failed: {}", attempt, mismatch);
     }
-    for mesh in tshirt_meshes {
-        draw_mesh(&mesh);
+    let Some(mut image) = captured else {
+        return Err(mismatch);
+    };
+
+    // Blending also runs on the alpha channel, which leaves semi-transparent edges slightly
+    // below 255 even over an opaque clear.
+    if background.is_some() {
+        for pixel in image.pixels_mut() {
+            pixel[3] = 255;
+        }
     }
 
-    let img_data = get_screen_data();
-    let width = img_data.width as u32;
-    let height = img_data.height as u32;
+    Ok(image::imageops::flip_vertical(&image))
+}
+
+// PNG_COMPRESSION trades encode time for size: `fast` encodes quickest but makes the biggest
+// files, `best` makes the smallest but is several times slower, `default` sits in between.
+fn load_png_compression() {
+    let compression = match env::var("PNG_COMPRESSION").as_deref() {
+        Err(_) | Ok("") | Ok("default") => Compression::Balanced,
+        Ok("fast") => Compression::Fast,
+        Ok("best") => Compression::High,
+        Ok(_) => panic!("PNG_COMPRESSION must be fast, default or best"),
+    };
+    let _ = PNG_COMPRESSION.set(compression);
+}
+
+// Caps what a malformed profile listing hundreds of items can make one render load.
+fn load_max_accessories() {
+    let max = match env::var("MAX_ACCESSORIES") {
+        Ok(value) if !value.is_empty() => value
+            .parse()
+            .expect("MAX_ACCESSORIES must be a number of accessories"),
+        _ => DEFAULT_MAX_ACCESSORIES,
+    };
+    let _ = MAX_ACCESSORIES.set(max);
+}
 
-    let Some(image) = image::RgbaImage::from_raw(width, height, img_data.bytes) else {
-        eprintln!("Failed to create image from screen data.");
-        return String::new();
+fn load_alpha_threshold() {
+    let threshold = match env::var("ALPHA_THRESHOLD") {
+        Ok(value) if !value.is_empty() => value
+            .parse()
+            .expect("ALPHA_THRESHOLD must be an alpha value from 0 to 255"),
+        _ => 0,
     };
+    let _ = ALPHA_THRESHOLD.set(threshold);
+}
 
-    let flipped_bytes = image::imageops::flip_vertical(&image).into_vec();
+fn encode_png(image: &image::RgbaImage, text: &[(String, String)]) -> Result<Vec<u8>, ServerError> {
     let mut png_data = Vec::new();
     {
-        let mut encoder = Encoder::new(&mut png_data, width, height);
+        let mut encoder = Encoder::new(&mut png_data, image.width(), image.height());
         encoder.set_color(ColorType::Rgba);
         encoder.set_depth(BitDepth::Eight);
-        match encoder.write_header() {
-            Ok(mut writer) => {
-                if let Err(e) = writer.write_image_data(&flipped_bytes) {
-                    eprintln!("Failed to write PNG data: {}", e);
-                    return String::new();
-                }
-            }
-            Err(e) => {
-                eprintln!("Failed to write PNG header: {}", e);
-                return String::new();
-            }
+        encoder.set_compression(PNG_COMPRESSION.get().copied().unwrap_or_default());
+        for (keyword, value) in text {
+            encoder
+                .add_text_chunk(keyword.clone(), value.clone())
+                .map_err(|e| {
+                    ServerError::RenderFailed(format!("PNG text chunk {}: {}", keyword, e))
+                })?;
+        }
+        encoder
+            .write_header()
+            .and_then(|mut writer| writer.write_image_data(image.as_raw()))
+            .map_err(|e| ServerError::RenderFailed(format!("PNG: {}", e)))?;
+    }
+
+    Ok(png_data)
+}
+
+fn encode_png_base64(image: &image::RgbaImage, text: &[(String, String)]) -> Option<String> {
+    match encode_png(image, text) {
+        Ok(png_data) => Some(base64::engine::general_purpose::STANDARD.encode(png_data)),
+        Err(e) => {
+            error!("{}", e);
+            None
+        }
+    }
+}
+
+fn encode_jpeg(image: &image::RgbaImage, quality: u8) -> Result<Vec<u8>, ServerError> {
+    let rgb = image::DynamicImage::ImageRgba8(image.clone()).to_rgb8();
+    let mut jpeg_data = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg_data, quality)
+        .encode_image(&rgb)
+        .map_err(|e| ServerError::RenderFailed(format!("JPEG: {}", e)))?;
+
+    Ok(jpeg_data)
+}
+
+fn decode_png_base64(image_b64: &str) -> Option<image::RgbaImage> {
+    let png_data = base64::engine::general_purpose::STANDARD
+        .decode(image_b64)
+        .ok()?;
+    match image::load_from_memory(&png_data) {
+        Ok(image) => Some(image.to_rgba8()),
+        Err(e) => {
+            error!("Failed to decode render: {}", e);
+            None
         }
     }
+}
+
+// Tiny blurred stand-in the client can show while the full image loads.
+fn make_lqip(image: &image::RgbaImage) -> Result<Vec<u8>, ServerError> {
+    let small = image::imageops::resize(image, 16, 16, image::imageops::FilterType::Triangle);
+    encode_png(&image::imageops::blur(&small, 1.0), &[])
+}
+
+// Renders go to an MSAA target, SUPERSAMPLE (1, 2 or 4, default 2) times RENDER_SIZE, and
+// get downscaled afterwards. GL2 and WebGL1 can't resolve a render target at all, those draw
+// straight to the window without anti-aliasing.
+fn offscreen_target() -> Option<RenderTarget> {
+    let factor = match env::var("SUPERSAMPLE") {
+        Ok(value) if !value.is_empty() => match value.parse::<u32>() {
+            Ok(factor @ (1 | 2 | 4)) => factor,
+            _ => panic!("SUPERSAMPLE must be 1, 2 or 4"),
+        },
+        _ => DEFAULT_SUPERSAMPLE,
+    };
+
+    let features = unsafe { get_internal_gl() }.quad_context.info().features;
+    if !features.resolve_attachments {
+        warn!("Backend can't resolve multisampled render targets, rendering without anti-aliasing");
+        return None;
+    }
+
+    let size = RENDER_SIZE as u32 * factor;
+    let target = render_target_ex(
+        size,
+        size,
+        RenderTargetParams {
+            sample_count: MSAA_SAMPLES,
+            depth: true,
+        },
+    );
+    target.texture.set_filter(FilterMode::Linear);
+    Some(target)
+}
 
-    base64::engine::general_purpose::STANDARD.encode(png_data)
+fn env_flag(name: &str) -> bool {
+    matches!(env::var(name).as_deref(), Ok("true") | Ok("1"))
 }
 
 fn window_conf() -> Conf {
     Conf {
         window_title: PROGRAM_NAME.to_owned(),
-        window_width: 1024,
-        window_height: 1024,
+        window_width: RENDER_SIZE,
+        window_height: RENDER_SIZE,
         window_resizable: false,
         ..Default::default()
     }
 }
 
-struct RenderRequest {
+// Everything the render loop needs for one job. Serializable so it can be handed to a worker
+// process, see workers.rs.
+#[derive(Serialize, Deserialize)]
+struct RenderJob {
     accessories: Vec<ItemAsset>,
     bodycolors: Option<BodyColors>,
+    // Colors given directly by an inline spec. Wins over bodycolors.
+    hex_colors: Option<HexBodyColors>,
     job_type: u8,
-    response_sender: Sender<String>,
+    options: SceneOptions,
+    output: OutputOptions,
     request_time: f64,
 }
 
-#[macroquad::main(window_conf)]
-async fn main() {
-    dotenv().ok();
-    println!("{}", PROGRAM_NAME);
-    println!("Licensed under the GPLv3.\n");
-
-    let (tx_work, rx_work) = channel::<RenderRequest>();
+impl RenderJob {
+    // Identifies renders that would produce the same output, whoever asked for them.
+    fn coalesce_key(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        serde_json::to_string(&(
+            &self.accessories,
+            &self.bodycolors,
+            &self.hex_colors,
+            self.job_type,
+            &self.options,
+            &self.output,
+        ))
+        .unwrap_or_default()
+        .hash(&mut hasher);
+        hasher.finish()
+    }
+}
 
-    let static_meshes = StaticMeshes {
-        head: load_static_mesh_from_bytes("default", DEFAULT_MESH_BYTES),
-        rarm: load_static_mesh_from_bytes("rightarm", RARM_MESH_BYTES),
-        larm: load_static_mesh_from_bytes("leftarm", LARM_MESH_BYTES),
-        rleg: load_static_mesh_from_bytes("rightleg", RLEG_MESH_BYTES),
-        lleg: load_static_mesh_from_bytes("leftleg", LLEG_MESH_BYTES),
-        trso: load_static_mesh_from_bytes("torso", TRSO_MESH_BYTES),
-        tshirt: load_static_mesh_from_bytes("tshirt", TSHIRT_MESH_BYTES),
+struct RenderRequest {
+    job: RenderJob,
+    response_sender: Sender<RenderAnswer>,
+}
+
+// What the render loop answers a job with.
+type RenderAnswer = Result<RenderOutput, ServerError>;
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct RenderOutput {
+    // From render_scene.
+    result: String,
+    // Accessories that loaded and went into the scene, for X-Accessory-Count.
+    accessories_drawn: usize,
+}
+
+enum RenderError {
+    // The render loop is gone.
+    ShuttingDown,
+    // No result within RENDER_TIMEOUT_SECS.
+    TimedOut,
+    // RENDER_QUEUE_SIZE jobs are already waiting.
+    Busy,
+    // The render loop answered, but without an image.
+    Failed(ServerError),
+}
+
+impl RenderError {
+    fn response(&self, id: i32, job_type: i32) -> rouille::Response {
+        match self {
+            RenderError::ShuttingDown => {
+                error_response(500, "SHUTTING_DOWN", "Fatal error, server shutting down.")
+            }
+            RenderError::TimedOut => {
+                error!("RENDER TIMED OUT. JOB TYPE: {}, ID: {}", job_type, id);
+                error_response(504, "RENDER_TIMEOUT", "Render timed out")
+            }
+            RenderError::Busy => {
+                warn!("QUEUE FULL, TURNED AWAY JOB TYPE: {}, ID: {}", job_type, id);
+                error_response(503, "SERVER_BUSY", "Server busy")
+            }
+            RenderError::Failed(e) => {
+                error!("RENDER FAILED. JOB TYPE: {}, ID: {}: {}", job_type, id, e);
+                e.response()
+            }
+        }
+    }
+}
+
+// How long a request waits on the render loop, and how old a queued job may get before the
+// render loop drops it instead of rendering for nobody.
+fn render_timeout() -> Duration {
+    match env::var("RENDER_TIMEOUT_SECS") {
+        Ok(value) if !value.is_empty() => Duration::from_secs(
+            value
+                .parse()
+                .expect("RENDER_TIMEOUT_SECS must be a number of seconds"),
+        ),
+        _ => DEFAULT_RENDER_TIMEOUT,
+    }
+}
+
+// Every connection is pinged before it's handed out, so ones that died while the pool sat idle
+// (or during a database outage) are dropped and replaced instead of failing the query.
+fn db_pool_options() -> MySqlPoolOptions {
+    let max_connections = match env::var("DB_MAX_CONNECTIONS") {
+        Ok(value) if !value.is_empty() => value
+            .parse::<u32>()
+            .ok()
+            .filter(|n| *n > 0)
+            .expect("DB_MAX_CONNECTIONS must be a positive number"),
+        _ => DEFAULT_DB_MAX_CONNECTIONS,
+    };
+    let acquire_timeout = match env::var("DB_ACQUIRE_TIMEOUT_SECS") {
+        Ok(value) if !value.is_empty() => Duration::from_secs(
+            value
+                .parse()
+                .expect("DB_ACQUIRE_TIMEOUT_SECS must be a number of seconds"),
+        ),
+        _ => DEFAULT_DB_ACQUIRE_TIMEOUT,
     };
 
+    MySqlPoolOptions::new()
+        .max_connections(max_connections)
+        .acquire_timeout(acquire_timeout)
+        .test_before_acquire(true)
+}
+
+// BIND_ADDR and PORT, 127.0.0.1:6767 unless set. Containers usually want BIND_ADDR=0.0.0.0.
+fn listen_address() -> SocketAddr {
+    let ip = match env::var("BIND_ADDR") {
+        Ok(value) if !value.is_empty() => value
+            .parse::<IpAddr>()
+            .expect("BIND_ADDR must be an IP address like 0.0.0.0"),
+        _ => DEFAULT_BIND_ADDR,
+    };
+    let port = match env::var("PORT") {
+        Ok(value) if !value.is_empty() => value.parse().expect("PORT must be a port number"),
+        _ => DEFAULT_PORT,
+    };
+    SocketAddr::new(ip, port)
+}
+
+// How many jobs may wait for the render loop before new requests are turned away with 503.
+fn queue_size() -> usize {
+    match env::var("RENDER_QUEUE_SIZE") {
+        Ok(value) if !value.is_empty() => value
+            .parse()
+            .ok()
+            .filter(|size| *size > 0)
+            .expect("RENDER_QUEUE_SIZE must be a positive number of jobs"),
+        _ => DEFAULT_QUEUE_SIZE,
+    }
+}
+
+// Renders a bare default avatar through the normal queue, so it proves the whole path works
+// (and pays for the first draw) in single-process and --workers mode alike.
+fn warm_up(tx_work: &SyncSender<RenderRequest>) -> bool {
+    let (tx_answer, rx_answer) = channel();
+    let job = RenderJob {
+        accessories: Vec::new(),
+        bodycolors: Some(BodyColors::default()),
+        hex_colors: None,
+        job_type: 1,
+        options: SceneOptions::default(),
+        output: OutputOptions::default(),
+        request_time: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f64(),
+    };
+    let request = RenderRequest {
+        job,
+        response_sender: tx_answer,
+    };
+
+    QUEUE_DEPTH.fetch_add(1, Ordering::Relaxed);
+    if tx_work.send(request).is_err() {
+        QUEUE_DEPTH.fetch_sub(1, Ordering::Relaxed);
+        return false;
+    }
+    rx_answer.recv().is_ok_and(|answer| answer.is_ok())
+}
+
+// /health reports ready once the DB is connected (run_server doesn't get this far otherwise),
+// a warm-up render went through, and READY_GRACE_SECS more have passed. SKIP_WARMUP leaves out
+// the render for quicker restarts in development, the first request pays for it instead.
+fn spawn_readiness(tx_work: SyncSender<RenderRequest>, ready: Arc<AtomicBool>) {
+    let grace = match env::var("READY_GRACE_SECS") {
+        Ok(secs) if !secs.is_empty() => secs
+            .parse::<u64>()
+            .expect("READY_GRACE_SECS must be a number of seconds"),
+        _ => 0,
+    };
+
+    let skip_warmup = env_flag("SKIP_WARMUP");
+
     thread::spawn(move || {
-        let rt = tokio::runtime::Runtime::new().expect("Failed to create Tokio runtime");
+        if !skip_warmup {
+            while !warm_up(&tx_work) {
+                warn!("Warm-up render failed, retrying in 5 seconds");
+                thread::sleep(Duration::from_secs(5));
+            }
+            info!("WARMUP COMPLETE");
+        }
+        thread::sleep(Duration::from_secs(grace));
+
+        ready.store(true, Ordering::Relaxed);
+        info!("READY");
+    });
+}
+
+// Profile colors and the resolved accessories for an avatar.
+fn fetch_avatar_render(
+    rt: &tokio::runtime::Runtime,
+    pool: &MySqlPool,
+    denied_items: &HashSet<i32>,
+    id: i32,
+) -> Result<(BodyColors, Vec<ItemAsset>), ServerError> {
+    // One block_on for both queries; the item lookup starts as soon as the profile is in.
+    // Only the profile has to be there, the avatar still renders bare when its items can't be
+    // looked up.
+    rt.block_on(async {
+        let (bodycolors, accessory_ids) = fetch_avatar(pool, id)
+            .await
+            .inspect_err(|e| error!("Failed to fetch user {}: {}", id, e))?;
+        let accessories = match fetch_accessories_info(pool, accessory_ids, denied_items).await {
+            Ok(a) => a,
+            Err(e) => {
+                error!("Failed to fetch accessories for user {}: {}", id, e);
+                Vec::new()
+            }
+        };
+
+        Ok((bodycolors, accessories))
+    })
+}
+
+fn arg_value<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|a| a == name)
+        .and_then(|i| args.get(i + 1))
+        .map(String::as_str)
+}
+
+fn main() {
+    dotenv().ok();
+    logger::init();
+    // Both the front-end (to validate profile colors) and the render loop need the palette.
+    load_brickcolor_palette();
+    load_png_compression();
+    load_max_accessories();
+    load_alpha_threshold();
+    let args: Vec<String> = env::args().collect();
+    let (tx_work, rx_work) = sync_channel::<RenderRequest>(queue_size());
+
+    // Spawned by a front-end started with --workers: take jobs from its socket instead of HTTP.
+    if let Some(socket_path) = arg_value(&args, "--worker") {
+        workers::serve_jobs(socket_path, tx_work);
+        macroquad::Window::from_config(window_conf(), render_loop(rx_work));
+        return;
+    }
+
+    println!("{}", PROGRAM_NAME);
+    println!("Licensed under the GPLv3.\n");
+
+    match arg_value(&args, "--workers") {
+        Some(count) => {
+            let count = count
+                .parse::<usize>()
+                .ok()
+                .filter(|c| *c > 0)
+                .expect("--workers expects a positive number");
+            workers::spawn_workers(count, rx_work);
+            run_server(tx_work);
+        }
+        None => {
+            thread::spawn(move || run_server(tx_work));
+            macroquad::Window::from_config(window_conf(), render_loop(rx_work));
+        }
+    }
+}
+
+// Everything the HTTP handlers share, set up once in run_server.
+struct ServerState {
+    rt: tokio::runtime::Runtime,
+    pool: Option<MySqlPool>,
+    tx_work: SyncSender<RenderRequest>,
+    in_flight: InFlight,
+    cache: RenderCache,
+    metrics: Metrics,
+    render_timeout: Duration,
+    output_dir: Option<OutputDir>,
+    denied_items: HashSet<i32>,
+    default_job_type: i32,
+    // Most item ids a bundle (job type 5) may list, from MAX_BUNDLE_ITEMS.
+    max_bundle_items: usize,
+    // Largest /preview upload, from PREVIEW_MAX_BYTES.
+    preview_max_bytes: usize,
+    anonymize_ips: bool,
+}
+
+impl ServerState {
+    // Answers from the cache, or queues a job (or joins an identical one already in flight)
+    // and waits for the result. With `persist` and OUTPUT_DIR set, a cached or freshly rendered
+    // result is written there; requests that joined the render leave that to the one that
+    // queued it, which writes before answering them so the files are there when they look.
+    fn submit_render(
+        &self,
+        job: RenderJob,
+        persist: Option<&RenderInfo>,
+    ) -> Result<RenderOutput, RenderError> {
+        let write = |output: &RenderOutput| {
+            if let (Some(dir), Some(info)) = (&self.output_dir, persist) {
+                dir.write_result(info, &output.result);
+            }
+        };
+
+        let key = job.coalesce_key();
+        if let Some(result) = self.cache.get(key) {
+            info!("SERVED FROM CACHE");
+            self.metrics.cache_hit();
+            write(&result);
+            return Ok(result);
+        }
+        self.metrics.cache_miss();
+        let (tx_answer, rx_answer) = channel();
+
+        if !self.in_flight.join(key, &tx_answer) {
+            debug!("JOINED IDENTICAL RENDER IN FLIGHT");
+            return match rx_answer.recv_timeout(self.render_timeout) {
+                Ok(Err(ServerError::TimedOut)) => Err(RenderError::TimedOut),
+                Ok(answer) => answer.map_err(RenderError::Failed),
+                Err(RecvTimeoutError::Timeout) => Err(RenderError::TimedOut),
+                Err(RecvTimeoutError::Disconnected) => Err(RenderError::ShuttingDown),
+            };
+        }
+
+        let job_type = job.job_type;
+        let request = RenderRequest {
+            job,
+            response_sender: tx_answer,
+        };
+        let queued = Instant::now();
+        QUEUE_DEPTH.fetch_add(1, Ordering::Relaxed);
+        if let Err(e) = self.tx_work.try_send(request) {
+            QUEUE_DEPTH.fetch_sub(1, Ordering::Relaxed);
+            self.in_flight.finish(
+                key,
+                &Err(ServerError::RenderFailed("render queue full".into())),
+            );
+            return Err(match e {
+                TrySendError::Full(_) => RenderError::Busy,
+                TrySendError::Disconnected(_) => RenderError::ShuttingDown,
+            });
+        }
+
+        // Followers time out along with the leader, the render loop will drop the job.
+        let answer = match rx_answer.recv_timeout(self.render_timeout) {
+            Ok(answer) => answer,
+            Err(RecvTimeoutError::Timeout) => {
+                self.in_flight.finish(key, &Err(ServerError::TimedOut));
+                return Err(RenderError::TimedOut);
+            }
+            Err(RecvTimeoutError::Disconnected) => Err(ServerError::RenderFailed(
+                "render loop went away without answering".into(),
+            )),
+        };
+        self.metrics
+            .render_done(job_type, queued.elapsed(), answer.is_ok());
+        if let Ok(output) = &answer {
+            self.cache.insert(key, output);
+            write(output);
+        }
+        self.in_flight.finish(key, &answer);
+        answer.map_err(RenderError::Failed)
+    }
+}
+
+// Form fields of a render request. Everything but the id is optional.
+#[derive(Default)]
+struct RenderParams {
+    id: String,
+    job_type: Option<String>,
+    cycle: Option<String>,
+    phase: Option<String>,
+    both_views: Option<String>,
+    lqip: Option<String>,
+    mirror: Option<String>,
+    background: Option<String>,
+    pass: Option<String>,
+    embed_metadata: Option<String>,
+    timings: Option<String>,
+    filter: Option<String>,
+    tiling: Option<String>,
+    face_transparency: Option<String>,
+    size: Option<String>,
+    format: Option<String>,
+    quality: Option<String>,
+    head_color: Option<String>,
+    trso_color: Option<String>,
+    larm_color: Option<String>,
+    rarm_color: Option<String>,
+    lleg_color: Option<String>,
+    rleg_color: Option<String>,
+    keep_clothing_alpha: Option<String>,
+    yaw: Option<String>,
+    pitch: Option<String>,
+    zoom: Option<String>,
+    return_path: Option<String>,
+    trim: Option<String>,
+    view: Option<String>,
+    projection: Option<String>,
+    head_scale: Option<String>,
+    torso_height: Option<String>,
+    limb_length: Option<String>,
+    show_face: Option<String>,
+    alpha_threshold: Option<String>,
+}
+
+impl RenderParams {
+    fn from_query(request: &rouille::Request) -> Option<RenderParams> {
+        Some(RenderParams {
+            id: request.get_param("id")?,
+            job_type: request.get_param("job_type"),
+            cycle: request.get_param("cycle"),
+            phase: request.get_param("phase"),
+            both_views: request.get_param("both_views"),
+            lqip: request.get_param("lqip"),
+            mirror: request.get_param("mirror"),
+            background: request.get_param("background"),
+            pass: request.get_param("pass"),
+            embed_metadata: request.get_param("embed_metadata"),
+            timings: request.get_param("timings"),
+            filter: request.get_param("filter"),
+            tiling: request.get_param("tiling"),
+            face_transparency: request.get_param("face_transparency"),
+            size: request.get_param("size"),
+            format: request.get_param("format"),
+            quality: request.get_param("quality"),
+            head_color: request.get_param("head_color"),
+            trso_color: request.get_param("trso_color"),
+            larm_color: request.get_param("larm_color"),
+            rarm_color: request.get_param("rarm_color"),
+            lleg_color: request.get_param("lleg_color"),
+            rleg_color: request.get_param("rleg_color"),
+            keep_clothing_alpha: request.get_param("keep_clothing_alpha"),
+            yaw: request.get_param("yaw"),
+            pitch: request.get_param("pitch"),
+            zoom: request.get_param("zoom"),
+            return_path: request.get_param("return_path"),
+            trim: request.get_param("trim"),
+            view: request.get_param("view"),
+            projection: request.get_param("projection"),
+            head_scale: request.get_param("head_scale"),
+            torso_height: request.get_param("torso_height"),
+            limb_length: request.get_param("limb_length"),
+            show_face: request.get_param("show_face"),
+            alpha_threshold: request.get_param("alpha_threshold"),
+        })
+    }
+}
+
+// Brickcolor ids sent with the request, each replacing that part's color from the profile or
+// outfit. Lets a customization UI preview colors without saving them.
+#[derive(Default)]
+struct BodyColorOverrides {
+    head: Option<u16>,
+    trso: Option<u16>,
+    larm: Option<u16>,
+    rarm: Option<u16>,
+    lleg: Option<u16>,
+    rleg: Option<u16>,
+}
+
+impl BodyColorOverrides {
+    // Err names the field that isn't a known brickcolor.
+    fn parse(params: &RenderParams) -> Result<BodyColorOverrides, &'static str> {
+        let parse = |name: &'static str, value: &Option<String>| match value.as_deref() {
+            None => Ok(None),
+            Some(value) => match value.parse::<u16>() {
+                Ok(id) if from_brickcolor(id).is_some() => Ok(Some(id)),
+                _ => Err(name),
+            },
+        };
+
+        Ok(BodyColorOverrides {
+            head: parse("head_color", &params.head_color)?,
+            trso: parse("trso_color", &params.trso_color)?,
+            larm: parse("larm_color", &params.larm_color)?,
+            rarm: parse("rarm_color", &params.rarm_color)?,
+            lleg: parse("lleg_color", &params.lleg_color)?,
+            rleg: parse("rleg_color", &params.rleg_color)?,
+        })
+    }
+
+    fn apply(&self, colors: &mut BodyColors) {
+        colors.head = self.head.unwrap_or(colors.head);
+        colors.trso = self.trso.unwrap_or(colors.trso);
+        colors.larm = self.larm.unwrap_or(colors.larm);
+        colors.rarm = self.rarm.unwrap_or(colors.rarm);
+        colors.lleg = self.lleg.unwrap_or(colors.lleg);
+        colors.rleg = self.rleg.unwrap_or(colors.rleg);
+    }
+}
+
+// One render in a POST /batch body.
+#[derive(Deserialize)]
+struct BatchEntry {
+    id: i32,
+    job_type: Option<i32>,
+}
+
+// Renders each entry with default options, one after the other, and maps id to either the
+// base64 image or an error, so one bad id doesn't sink the rest.
+fn handle_batch(state: &ServerState, request: &rouille::Request) -> rouille::Response {
+    let entries: Vec<BatchEntry> = match rouille::input::json_input(request) {
+        Ok(entries) => entries,
+        Err(e) => return error_response(400, "INVALID_BATCH", format!("Invalid batch: {}", e)),
+    };
+    if entries.len() > MAX_BATCH_SIZE {
+        return error_response(
+            400,
+            "BATCH_TOO_LARGE",
+            format!("At most {} renders per batch", MAX_BATCH_SIZE),
+        );
+    }
+
+    // The whole batch gets one RENDER_TIMEOUT_SECS, entries still waiting after that aren't
+    // rendered so a big batch can't hold the connection for minutes.
+    let deadline = Instant::now() + state.render_timeout;
+    let mut results = serde_json::Map::new();
+    for entry in entries {
+        let result = if Instant::now() >= deadline {
+            batch_error(RenderError::TimedOut.response(entry.id, entry.job_type.unwrap_or(0)))
+        } else {
+            batch_entry(state, request, &entry)
+        };
+        results.insert(entry.id.to_string(), result);
+    }
+
+    rouille::Response::json(&results)
+}
+
+// Renders one entry with default options. Entries go straight to the queue rather than through
+// the response, so the batch always gets base64 whatever the client's Accept header says.
+fn batch_entry(
+    state: &ServerState,
+    request: &rouille::Request,
+    entry: &BatchEntry,
+) -> serde_json::Value {
+    let params = RenderParams {
+        id: entry.id.to_string(),
+        job_type: entry.job_type.map(|t| t.to_string()),
+        ..Default::default()
+    };
+    let render = match prepare_render(state, request, params) {
+        Ok(render) => render,
+        Err(response) => return batch_error(response),
+    };
+
+    let info = render.info();
+    match state.submit_render(render.job, Some(&info)) {
+        Ok(output) => output.result.into(),
+        Err(e) => batch_error(e.response(info.id, info.job_type)),
+    }
+}
 
+// An error response as a batch entry: its JSON body plus the status it would have had.
+fn batch_error(response: rouille::Response) -> serde_json::Value {
+    let status = response.status_code;
+    let (mut body, _) = response.data.into_reader_and_size();
+    let mut text = String::new();
+    let mut error = match body.read_to_string(&mut text) {
+        Ok(_) => serde_json::from_str::<serde_json::Value>(&text)
+            .unwrap_or_else(|_| serde_json::json!({ "error": text })),
+        Err(e) => serde_json::json!({ "error": format!("Unreadable error response: {}", e) }),
+    };
+    error["status"] = status.into();
+    error
+}
+
+// The body length a request is held to: its Content-Length, which the HTTP server never reads
+// past. None when there's no bound, either because there's no Content-Length or because
+// chunked encoding or a connection upgrade makes the server ignore it.
+fn body_length(request: &rouille::Request) -> Option<usize> {
+    let unbounded = request.header("Transfer-Encoding").is_some()
+        || request
+            .header("Connection")
+            .is_some_and(|connection| connection.to_ascii_lowercase().contains("upgrade"));
+    if unbounded {
+        return None;
+    }
+    request.header("Content-Length")?.parse().ok()
+}
+
+// Whether the request carries `Authorization: Bearer <ADMIN_TOKEN>`. Always false without a
+// token configured.
+fn admin_authorized(admin_token: &Option<String>, request: &rouille::Request) -> bool {
+    match (admin_token, request.header("Authorization")) {
+        (Some(token), Some(header)) => header.strip_prefix("Bearer ") == Some(token.as_str()),
+        _ => false,
+    }
+}
+
+// Errors are JSON with a stable `code` for clients to match on; `error` is for humans and may
+// change.
+fn error_response(status: u16, code: &str, message: impl Into<String>) -> rouille::Response {
+    rouille::Response::json(&serde_json::json!({
+        "error": message.into(),
+        "code": code,
+    }))
+    .with_status_code(status)
+}
+
+// Turns a render loop result into the response. Single images are base64 text, or the raw PNG
+// for clients that send `Accept: image/png` (so the URL can go straight into an <img>).
+// Successful responses carry X-Render-Time-Ms, the time since request_time (queue plus
+// render), and X-Peak-Mem-KB, the server process's peak resident memory. Single avatar renders
+// also get X-Accessory-Count, the accessories actually drawn, so a bare avatar (0) can be told
+// apart from one whose items failed to load.
+fn render_response(
+    request: &rouille::Request,
+    result: String,
+    accessories_drawn: Option<usize>,
+    is_json: bool,
+    trim: bool,
+    format: OutputFormat,
+    request_time: f64,
+) -> rouille::Response {
+    let (result, crop_offset) = if trim {
+        split_crop(result, is_json)
+    } else {
+        (result, None)
+    };
+
+    let wants_bytes = request
+        .header("Accept")
+        .is_some_and(|accept| accept.contains(format.mime_type()));
+    let response = if is_json {
+        rouille::Response::from_data("application/json", result)
+    } else if wants_bytes {
+        match base64::engine::general_purpose::STANDARD.decode(&result) {
+            Ok(data) => rouille::Response::from_data(format.mime_type(), data),
+            Err(e) => {
+                error!("Render result wasn't valid base64: {}", e);
+                return error_response(500, "RENDER_FAILED", "Render Failed");
+            }
+        }
+    } else {
+        rouille::Response::text(result)
+    };
+
+    let elapsed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs_f64()
+        - request_time;
+    let mut response =
+        response.with_additional_header("X-Render-Time-Ms", format!("{:.0}", elapsed * 1000.0));
+    if let Some(offset) = crop_offset {
+        response = response.with_additional_header("X-Crop-Offset", offset);
+    }
+    if let Some(count) = accessories_drawn {
+        response = response.with_additional_header("X-Accessory-Count", count.to_string());
+    }
+    match peak_memory_kb() {
+        Some(kb) => response.with_additional_header("X-Peak-Mem-KB", kb.to_string()),
+        None => response,
+    }
+}
+
+// Renders the job, writes it to OUTPUT_DIR if that's set (see submit_render) and answers with
+// the result. With return_path the answer is the written files instead, as
+// {"<view>": "<path in OUTPUT_DIR>"}, and when OUTPUT_PATH_TEMPLATE includes {hash} files
+// already on disk skip the render.
+fn finish_render(
+    state: &ServerState,
+    request: &rouille::Request,
+    render: PreparedRender,
+) -> rouille::Response {
+    let info = render.info();
+    let PreparedRender {
+        job,
+        id,
+        view,
+        return_path,
+    } = render;
+    let output = job.output;
+    let request_time = job.request_time;
+    let job_type = job.job_type as i32;
+
+    if return_path && let Some(dir) = &state.output_dir {
+        let views: &[&str] = if output.both_views {
+            &["fullbody", "headshot"]
+        } else {
+            &[view]
+        };
+        if let Some(paths) = dir.existing(&info, views) {
+            info!("ALREADY ON DISK");
+            return path_response(paths);
+        }
+    }
+
+    let result = match state.submit_render(job, Some(&info)) {
+        Ok(result) => result,
+        Err(e) => return e.response(id, job_type),
+    };
+
+    let written = match &state.output_dir {
+        Some(dir) => dir.result_paths(&info, &result.result),
+        _ => Vec::new(),
+    };
+    if return_path {
+        if written.is_empty() {
+            return error_response(500, "RENDER_FAILED", "Render Failed");
+        }
+        return path_response(written);
+    }
+
+    render_response(
+        request,
+        result.result,
+        Some(result.accessories_drawn),
+        output.is_json(),
+        output.trim,
+        output.format,
+        request_time,
+    )
+}
+
+// Pulls the main view's crop out of a trimmed result as "x,y". Clients that didn't ask for JSON
+// get just the image back, the rest keep the whole object, crops of every view included.
+fn split_crop(result: String, is_json: bool) -> (String, Option<String>) {
+    let Ok(serde_json::Value::Object(mut map)) = serde_json::from_str(&result) else {
+        return (result, None);
+    };
+    let offset = map
+        .get("crop")
+        .and_then(|crop| crop.get("image").or_else(|| crop.get("fullbody")))
+        .map(|crop| format!("{},{}", crop["x"], crop["y"]));
+    if is_json {
+        return (result, offset);
+    }
+    match map.remove("image") {
+        Some(serde_json::Value::String(image)) => (image, offset),
+        _ => (String::new(), offset),
+    }
+}
+
+fn path_response(paths: Vec<(String, String)>) -> rouille::Response {
+    let paths: serde_json::Map<String, serde_json::Value> = paths
+        .into_iter()
+        .map(|(view, path)| (view, path.into()))
+        .collect();
+    rouille::Response::json(&serde_json::Value::Object(paths))
+}
+
+// Err names the field that isn't a number in Proportions::RANGE.
+fn parse_proportions(params: &RenderParams) -> Result<Proportions, &'static str> {
+    let (min, max) = Proportions::RANGE;
+    let parse = |name: &'static str, value: &Option<String>| match value.as_deref() {
+        None => Ok(1.0),
+        Some(value) => match value.parse::<f32>() {
+            Ok(v) if (min..=max).contains(&v) => Ok(v),
+            _ => Err(name),
+        },
+    };
+
+    Ok(Proportions {
+        head: parse("head_scale", &params.head_scale)?,
+        torso_height: parse("torso_height", &params.torso_height)?,
+        limb_length: parse("limb_length", &params.limb_length)?,
+    })
+}
+
+// Users, items and outfits are numbered from 1, so anything else is turned away before it costs
+// a database round trip.
+fn parse_id(id: &str) -> Option<i32> {
+    id.trim().parse::<i32>().ok().filter(|id| *id > 0)
+}
+
+// Shared by POST / and GET /render, which only differ in where the parameters come from.
+fn handle_render(
+    state: &ServerState,
+    request: &rouille::Request,
+    params: RenderParams,
+) -> rouille::Response {
+    match prepare_render(state, request, params) {
+        Ok(render) => finish_render(state, request, render),
+        Err(response) => response,
+    }
+}
+
+// A validated render request with its data fetched, ready for the queue.
+struct PreparedRender {
+    job: RenderJob,
+    id: i32,
+    // Where OUTPUT_DIR files it.
+    view: &'static str,
+    return_path: bool,
+}
+
+impl PreparedRender {
+    // Names the result under OUTPUT_DIR.
+    fn info(&self) -> RenderInfo {
+        RenderInfo {
+            id: self.id,
+            job_type: self.job.job_type as i32,
+            size: self.job.output.size.unwrap_or(RENDER_SIZE as u32),
+            hash: self.job.coalesce_key(),
+            ext: self.job.output.format.extension(),
+            view: self.view,
+        }
+    }
+}
+
+// Parses the params and fetches what the job type needs. Err is the response to send instead.
+fn prepare_render(
+    state: &ServerState,
+    request: &rouille::Request,
+    params: RenderParams,
+) -> Result<PreparedRender, rouille::Response> {
+    let Some(pool) = &state.pool else {
+        return Err(error_response(
+            503,
+            "DATABASE_DISABLED",
+            "Database disabled",
+        ));
+    };
+    let current_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs_f64();
+    let client = if state.anonymize_ips {
+        mask_ip(request.remote_addr().ip()).to_string()
+    } else {
+        request.remote_addr().to_string()
+    };
+    info!("INCOMING -- FROM {}", client);
+
+    let type_val = match params.job_type.as_deref().map(str::parse::<i32>) {
+        None => state.default_job_type,
+        Some(Ok(i)) if JOB_TYPES.contains(&i) => i,
+        Some(_) => return Err(error_response(400, "INVALID_JOB_TYPE", "Invalid job_type")),
+    };
+
+    // Bundles take a comma separated list of item ids, everything else exactly one id. Bundle
+    // renders are logged and stored under the first id.
+    let ids = match params
+        .id
+        .split(',')
+        .map(parse_id)
+        .collect::<Option<Vec<_>>>()
+    {
+        Some(ids) if ids.len() == 1 || type_val == 5 => ids,
+        _ => return Err(error_response(400, "INVALID_ID", "Invalid id")),
+    };
+    if type_val == 5 && ids.len() > state.max_bundle_items {
+        return Err(error_response(
+            400,
+            "TOO_MANY_ITEMS",
+            format!("A bundle takes at most {} items", state.max_bundle_items),
+        ));
+    }
+    let id_val = ids[0];
+
+    let pose = match params.cycle.as_deref() {
+        None => Pose::default(),
+        Some(name) => {
+            let Some(animation) = Animation::from_name(name) else {
+                return Err(error_response(400, "INVALID_CYCLE", "Invalid cycle"));
+            };
+            let phase = match params.phase.as_deref().unwrap_or("0").parse::<f32>() {
+                Ok(p) if p.is_finite() => p,
+                _ => return Err(error_response(400, "INVALID_PHASE", "Invalid phase")),
+            };
+            animation.sample(phase)
+        }
+    };
+
+    let color_overrides = match BodyColorOverrides::parse(&params) {
+        Ok(overrides) => overrides,
+        Err(field) => {
+            return Err(error_response(
+                400,
+                "INVALID_COLOR",
+                format!("Invalid {}", field),
+            ));
+        }
+    };
+
+    let camera = match CameraAngle::parse(&params) {
+        Ok(camera) => camera,
+        Err(field) => {
+            return Err(error_response(
+                400,
+                "INVALID_CAMERA",
+                format!("Invalid {}", field),
+            ));
+        }
+    };
+
+    let texture_filter = match params.filter.as_deref() {
+        None | Some("linear") => TextureFilter::Linear,
+        Some("nearest") => TextureFilter::Nearest,
+        Some(_) => return Err(error_response(400, "INVALID_FILTER", "Invalid filter")),
+    };
+
+    let tiling = match params.tiling.as_deref().unwrap_or("1").parse::<f32>() {
+        Ok(t) if t.is_finite() && t > 0.0 && t <= 64.0 => t,
+        _ => return Err(error_response(400, "INVALID_TILING", "Invalid tiling")),
+    };
+
+    let alpha_threshold = match params.alpha_threshold.as_deref() {
+        None => ALPHA_THRESHOLD.get().copied().unwrap_or_default(),
+        Some(value) => match value.parse::<u8>() {
+            Ok(threshold) => threshold,
+            Err(_) => {
+                return Err(error_response(
+                    400,
+                    "INVALID_ALPHA_THRESHOLD",
+                    "Invalid alpha_threshold",
+                ));
+            }
+        },
+    };
+
+    let proportions = match parse_proportions(&params) {
+        Ok(proportions) => proportions,
+        Err(field) => {
+            return Err(error_response(
+                400,
+                "INVALID_PROPORTIONS",
+                format!("Invalid {}", field),
+            ));
+        }
+    };
+
+    let Some(face_transparency) =
+        FaceTransparency::from_name(params.face_transparency.as_deref().unwrap_or("auto"))
+    else {
+        return Err(error_response(
+            400,
+            "INVALID_FACE_TRANSPARENCY",
+            "Invalid face_transparency",
+        ));
+    };
+
+    let options = SceneOptions {
+        pose,
+        proportions,
+        texture_filter,
+        tiling,
+        face_transparency,
+        keep_clothing_alpha: matches!(
+            params.keep_clothing_alpha.as_deref(),
+            Some("true") | Some("1")
+        ),
+        show_face: !matches!(params.show_face.as_deref(), Some("false") | Some("0")),
+        alpha_threshold,
+    };
+
+    let format = match params.format.as_deref() {
+        None | Some("png") => OutputFormat::Png,
+        Some("jpeg") => match params.quality.as_deref().map(str::parse::<u8>) {
+            None => OutputFormat::Jpeg {
+                quality: JPEG_DEFAULT_QUALITY,
+            },
+            Some(Ok(quality)) if (1..=100).contains(&quality) => OutputFormat::Jpeg { quality },
+            Some(_) => return Err(error_response(400, "INVALID_QUALITY", "Invalid quality")),
+        },
+        Some(_) => return Err(error_response(400, "INVALID_FORMAT", "Invalid format")),
+    };
+
+    let background = match params.background.as_deref() {
+        None => None,
+        Some(value) => match parse_hex_color(value) {
+            Some(hex) => Some(hex),
+            None => {
+                return Err(error_response(
+                    400,
+                    "INVALID_BACKGROUND",
+                    "Invalid background",
+                ));
+            }
+        },
+    };
+    // JPEG can't keep the transparency, so it gets white unless asked otherwise.
+    let background = match format {
+        OutputFormat::Jpeg { .. } => background.or(Some(0xFFFFFF)),
+        OutputFormat::Png => background,
+    };
+
+    let pass = match params.pass.as_deref() {
+        None => RenderPass::Color,
+        Some(name) => match RenderPass::from_name(name) {
+            Some(pass) => pass,
+            None => return Err(error_response(400, "INVALID_PASS", "Invalid pass")),
+        },
+    };
+
+    let size = match params.size.as_deref() {
+        None => None,
+        Some(value) => match value.parse::<u32>() {
+            Ok(size) if OUTPUT_SIZES.contains(&size) => Some(size),
+            _ => return Err(error_response(400, "INVALID_SIZE", "Invalid size")),
+        },
+    };
+
+    let return_path = matches!(params.return_path.as_deref(), Some("true") | Some("1"));
+    if return_path && state.output_dir.is_none() {
+        return Err(error_response(
+            503,
+            "OUTPUT_DIR_DISABLED",
+            "return_path needs OUTPUT_DIR to be set",
+        ));
+    }
+
+    let output = OutputOptions {
+        both_views: matches!(params.both_views.as_deref(), Some("true") | Some("1")),
+        lqip: matches!(params.lqip.as_deref(), Some("true") | Some("1")),
+        mirror: matches!(params.mirror.as_deref(), Some("true") | Some("1")),
+        background,
+        pass,
+        metadata: matches!(params.embed_metadata.as_deref(), Some("true") | Some("1")).then_some(
+            MetadataSubject {
+                id: id_val,
+                job_type: type_val,
+            },
+        ),
+        timings: matches!(params.timings.as_deref(), Some("true") | Some("1")),
+        size,
+        format,
+        camera,
+        trim: matches!(params.trim.as_deref(), Some("true") | Some("1")),
+    };
+
+    info!("JOB TYPE: {}, ID: {}. REQUESTING RENDER", type_val, id_val);
+
+    match type_val {
+        // 4 is an avatar headshot. It isn't 3 because outfit templates already had that.
+        1 | 4 => {
+            let (mut bodycolors, accessories) =
+                match fetch_avatar_render(&state.rt, pool, &state.denied_items, id_val) {
+                    Ok(data) => data,
+                    Err(e) => return Err(e.response()),
+                };
+            color_overrides.apply(&mut bodycolors);
+
+            // A headshot is already the close view, there's no second one to add.
+            let output = OutputOptions {
+                both_views: output.both_views && type_val == 1,
+                ..output
+            };
+            let job = RenderJob {
+                accessories,
+                bodycolors: Some(bodycolors),
+                hex_colors: None,
+                job_type: type_val as u8,
+                options,
+                output,
+                request_time: current_time,
+            };
+
+            let view = if type_val == 4 {
+                "headshot"
+            } else {
+                "fullbody"
+            };
+            Ok(PreparedRender {
+                job,
+                id: id_val,
+                view,
+                return_path,
+            })
+        }
+        2 => {
+            let accessories = match state.rt.block_on(fetch_accessories_info(
+                pool,
+                vec![id_val],
+                &state.denied_items,
+            )) {
+                Ok(a) => a,
+                Err(e) => {
+                    error!("Failed to fetch item {}: {}", id_val, e);
+                    return Err(e.response());
+                }
+            };
+
+            // Unapproved or denied items come back empty; the render loop can't draw nothing.
+            if accessories.is_empty() {
+                return Err(ServerError::NotFound(Missing::Item).response());
+            }
+
+            let job = RenderJob {
+                accessories,
+                bodycolors: None,
+                hex_colors: None,
+                job_type: 2,
+                options,
+                output: OutputOptions {
+                    both_views: false,
+                    ..output
+                },
+                request_time: current_time,
+            };
+
+            Ok(PreparedRender {
+                job,
+                id: id_val,
+                view: "fullbody",
+                return_path,
+            })
+        }
+        3 => {
+            let outfit_result = state
+                .rt
+                .block_on(async { fetch_outfit(pool, id_val).await });
+
+            let (mut bodycolors, accessory_ids) = match outfit_result {
+                Ok(data) => data,
+                Err(e) => {
+                    error!("Failed to fetch outfit {}: {}", id_val, e);
+                    return Err(e.response());
+                }
+            };
+            color_overrides.apply(&mut bodycolors);
+
+            let accessories = match state.rt.block_on(fetch_accessories_info(
+                pool,
+                accessory_ids,
+                &state.denied_items,
+            )) {
+                Ok(a) => a,
+                Err(e) => {
+                    error!("Failed to fetch accessories for outfit {}: {}", id_val, e);
+                    Vec::new()
+                }
+            };
+
+            let job = RenderJob {
+                accessories,
+                bodycolors: Some(bodycolors),
+                hex_colors: None,
+                job_type: 3,
+                options,
+                output,
+                request_time: current_time,
+            };
+
+            Ok(PreparedRender {
+                job,
+                id: id_val,
+                view: "fullbody",
+                return_path,
+            })
+        }
+        5 => {
+            let accessories =
+                match state
+                    .rt
+                    .block_on(fetch_accessories_info(pool, ids, &state.denied_items))
+                {
+                    Ok(a) => a,
+                    Err(e) => {
+                        error!("Failed to fetch items for bundle {}: {}", params.id, e);
+                        return Err(e.response());
+                    }
+                };
+
+            // Missing ids are left out of the render, only a bundle with none found is a 404.
+            if accessories.is_empty() {
+                return Err(ServerError::NotFound(Missing::Item).response());
+            }
+
+            let job = RenderJob {
+                accessories,
+                bodycolors: None,
+                hex_colors: None,
+                job_type: 5,
+                options,
+                output: OutputOptions {
+                    both_views: false,
+                    ..output
+                },
+                request_time: current_time,
+            };
+
+            Ok(PreparedRender {
+                job,
+                id: id_val,
+                view: "fullbody",
+                return_path,
+            })
+        }
+        _ => {
+            warn!("they just tried requesting a bunch of hippy dippy baloney");
+            Err(error_response(400, "INVALID_JOB_TYPE", "Invalid job type"))
+        }
+    }
+}
+
+fn run_server(tx_work: SyncSender<RenderRequest>) {
+    let rt = tokio::runtime::Runtime::new().expect("Failed to create Tokio runtime");
+
+    let anonymize_ips = env_flag("ANONYMIZE_IPS");
+    let default_job_type = match env::var("DEFAULT_JOB_TYPE") {
+        Ok(value) if !value.is_empty() => value
+            .parse::<i32>()
+            .ok()
+            .filter(|job_type| JOB_TYPES.contains(job_type))
+            .expect("DEFAULT_JOB_TYPE must be a job type number from 1 to 5"),
+        _ => 1,
+    };
+    let max_bundle_items = match env::var("MAX_BUNDLE_ITEMS") {
+        Ok(value) if !value.is_empty() => value
+            .parse::<usize>()
+            .expect("MAX_BUNDLE_ITEMS must be a number of items"),
+        _ => DEFAULT_MAX_BUNDLE_ITEMS,
+    };
+    let preview_max_bytes = match env::var("PREVIEW_MAX_BYTES") {
+        Ok(value) if !value.is_empty() => value
+            .parse::<usize>()
+            .expect("PREVIEW_MAX_BYTES must be a number of bytes"),
+        _ => DEFAULT_PREVIEW_MAX_BYTES,
+    };
+
+    // With DB_DISABLED only /inline can render; the id based routes answer 503.
+    let pool = if env_flag("DB_DISABLED") {
+        info!("Database disabled, only inline renders are available");
+        None
+    } else {
         let db_password = env::var("DB_PASSWORD").expect("DB_PASSWORD not set in .env file");
         let db_username = env::var("DB_USERNAME").expect("DB_USERNAME not set in .env file");
         let db_address = env::var("DB_ADDRESS").expect("DB_ADDRESS not set in .env file");
@@ -293,161 +2601,567 @@ async fn main() {
             db_username, db_password, db_address, db_dbname
         );
 
-        let pool = rt.block_on(async {
-            MySqlPool::connect(&url)
+        Some(rt.block_on(async {
+            let pool = db_pool_options()
+                .connect(&url)
                 .await
-                .expect("Failed to connect to DB")
-        });
+                .expect("Failed to connect to DB");
+            detect_item_scale_column(&pool).await;
+            pool
+        }))
+    };
 
-        let now: DateTime<Utc> = Utc::now();
-        println!(
-            "[{}] STARTED SERVER ON PORT 6767 (unfunny)",
-            now.format("%d-%m-%Y %H:%M:%S")
-        );
+    let in_flight = InFlight::default();
+    let ready = Arc::new(AtomicBool::new(false));
+    spawn_readiness(tx_work.clone(), Arc::clone(&ready));
+    let output_dir = OutputDir::from_env();
+    if let Some(dir) = &output_dir {
+        dir.spawn_pruner();
+    }
+    let denied_items = load_denied_items();
+    if !denied_items.is_empty() {
+        info!("{} item ids on the deny list", denied_items.len());
+    }
 
-        rouille::start_server("127.0.0.1:6767", move |request| {
-            router!(request,
-                (POST) (/) => {
-                    let current_time = SystemTime::now()
-                        .duration_since(UNIX_EPOCH)
-                        .unwrap()
-                        .as_secs_f64();
-                    let now: DateTime<Utc> = Utc::now();
-                    println!("[{}] INCOMING -- FROM {:?}", now.format("%d-%m-%Y %H:%M:%S"), request.remote_addr());
+    let state = ServerState {
+        rt,
+        pool,
+        tx_work,
+        in_flight,
+        cache: RenderCache::from_env(),
+        metrics: Metrics::default(),
+        render_timeout: render_timeout(),
+        output_dir,
+        denied_items,
+        default_job_type,
+        max_bundle_items,
+        preview_max_bytes,
+        anonymize_ips,
+    };
+
+    let maintenance = AtomicBool::new(false);
+    let started = Instant::now();
+    let admin_token = env::var("ADMIN_TOKEN").ok().filter(|t| !t.is_empty());
 
+    let handler = move |request: &rouille::Request| {
+        // Maintenance turns away new renders; ones already queued or rendering still finish.
+        if maintenance.load(Ordering::Relaxed)
+            && request.url() != "/health"
+            && request.url() != "/version"
+            && request.url() != "/metrics"
+            && !request.url().starts_with("/admin/")
+        {
+            return error_response(503, "MAINTENANCE", "Down for maintenance")
+                .with_additional_header("Retry-After", MAINTENANCE_RETRY_AFTER);
+        }
 
-                    let body = match post_input!(request, { id: String, job_type: String }) {
-                        Ok(d) => d,
-                        Err(_) => return rouille::Response::empty_400(),
+        router!(request,
+            (GET) (/health) => {
+                // None when running with DB_DISABLED, there's nothing to be connected to.
+                let database = state.pool.as_ref().map(|pool| {
+                    state.rt.block_on(ping_database(pool, HEALTH_DB_TIMEOUT))
+                });
+                let (status, code) = if maintenance.load(Ordering::Relaxed) {
+                    ("maintenance", 503)
+                } else if !ready.load(Ordering::Relaxed) {
+                    ("not ready", 503)
+                } else if database == Some(false) {
+                    ("database unreachable", 503)
+                } else {
+                    ("ok", 200)
+                };
+
+                rouille::Response::json(&serde_json::json!({
+                    "name": PROGRAM_NAME,
+                    "status": status,
+                    "uptime_secs": started.elapsed().as_secs(),
+                    "database": database,
+                    "queue_depth": QUEUE_DEPTH.load(Ordering::Relaxed),
+                }))
+                .with_status_code(code)
+            },
+            (GET) (/metrics) => {
+                let text = state.metrics.render_text(QUEUE_DEPTH.load(Ordering::Relaxed), peak_memory_kb());
+                rouille::Response::from_data("text/plain; version=0.0.4", text)
+            },
+            (GET) (/version) => {
+                rouille::Response::json(&serde_json::json!({
+                    "name": PROGRAM_NAME,
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "commit": env!("BUILD_GIT_COMMIT"),
+                    "built_at": env!("BUILD_TIMESTAMP"),
+                }))
+            },
+            (POST) (/admin/maintenance) => {
+                if !admin_authorized(&admin_token, request) {
+                    return error_response(401, "UNAUTHORIZED", "Missing or wrong admin token");
+                }
+
+                let body = match post_input!(request, { enabled: Option<String> }) {
+                    Ok(d) => d,
+                    Err(_) => return error_response(400, "INVALID_BODY", "Malformed form body"),
+                };
+                let enabled = match body.enabled.as_deref() {
+                    None => !maintenance.load(Ordering::Relaxed),
+                    Some("true") | Some("1") => true,
+                    Some("false") | Some("0") => false,
+                    Some(_) => return error_response(400, "INVALID_ENABLED", "enabled must be true or false"),
+                };
+                maintenance.store(enabled, Ordering::Relaxed);
+
+                info!("MAINTENANCE MODE {}", if enabled { "ON" } else { "OFF" });
+                rouille::Response::text(if enabled { "maintenance on" } else { "maintenance off" })
+            },
+            (POST) (/) => {
+                let body = match post_input!(request, {
+                    id: String,
+                    job_type: Option<String>,
+                    cycle: Option<String>,
+                    phase: Option<String>,
+                    both_views: Option<String>,
+                    lqip: Option<String>,
+                    mirror: Option<String>,
+                    background: Option<String>,
+                    pass: Option<String>,
+                    embed_metadata: Option<String>,
+                    timings: Option<String>,
+                    filter: Option<String>,
+                    tiling: Option<String>,
+                    face_transparency: Option<String>,
+                    size: Option<String>,
+                    format: Option<String>,
+                    quality: Option<String>,
+                    head_color: Option<String>,
+                    trso_color: Option<String>,
+                    larm_color: Option<String>,
+                    rarm_color: Option<String>,
+                    lleg_color: Option<String>,
+                    rleg_color: Option<String>,
+                    keep_clothing_alpha: Option<String>,
+                    yaw: Option<String>,
+                    pitch: Option<String>,
+                    zoom: Option<String>,
+                    return_path: Option<String>,
+                    trim: Option<String>,
+                    view: Option<String>,
+                    projection: Option<String>,
+                    head_scale: Option<String>,
+                    torso_height: Option<String>,
+                    limb_length: Option<String>,
+                    show_face: Option<String>,
+                    alpha_threshold: Option<String>,
+                }) {
+                    Ok(d) => d,
+                    Err(_) => return error_response(400, "INVALID_BODY", "Missing id or malformed form body"),
+                };
+                let params = RenderParams {
+                    id: body.id,
+                    job_type: body.job_type,
+                    cycle: body.cycle,
+                    phase: body.phase,
+                    both_views: body.both_views,
+                    lqip: body.lqip,
+                    mirror: body.mirror,
+                    background: body.background,
+                    pass: body.pass,
+                    embed_metadata: body.embed_metadata,
+                    timings: body.timings,
+                    filter: body.filter,
+                    tiling: body.tiling,
+                    face_transparency: body.face_transparency,
+                    size: body.size,
+                    format: body.format,
+                    quality: body.quality,
+                    head_color: body.head_color,
+                    trso_color: body.trso_color,
+                    larm_color: body.larm_color,
+                    rarm_color: body.rarm_color,
+                    lleg_color: body.lleg_color,
+                    rleg_color: body.rleg_color,
+                    keep_clothing_alpha: body.keep_clothing_alpha,
+                    yaw: body.yaw,
+                    pitch: body.pitch,
+                    zoom: body.zoom,
+                    return_path: body.return_path,
+                    trim: body.trim,
+                    view: body.view,
+                    projection: body.projection,
+                    head_scale: body.head_scale,
+                    torso_height: body.torso_height,
+                    limb_length: body.limb_length,
+                    show_face: body.show_face,
+                    alpha_threshold: body.alpha_threshold,
+                };
+                handle_render(&state, request, params)
+            },
+            (GET) (/render) => {
+                let Some(params) = RenderParams::from_query(request) else {
+                    return error_response(400, "MISSING_ID", "Missing id");
+                };
+                handle_render(&state, request, params)
+            },
+            (POST) (/compare) => {
+                let Some(pool) = &state.pool else {
+                    return error_response(503, "DATABASE_DISABLED", "Database disabled");
+                };
+                let current_time = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs_f64();
+
+                let body = match post_input!(request, {
+                    id_a: String,
+                    id_b: String,
+                    mode: Option<String>,
+                }) {
+                    Ok(d) => d,
+                    Err(_) => return error_response(400, "INVALID_BODY", "Missing id_a or id_b or malformed form body"),
+                };
+
+                let (Some(id_a), Some(id_b)) = (parse_id(&body.id_a), parse_id(&body.id_b)) else {
+                    return error_response(400, "INVALID_ID", "Invalid id_a or id_b");
+                };
+
+                let Some(mode) = CompareMode::from_name(body.mode.as_deref().unwrap_or("sidebyside")) else {
+                    return error_response(400, "INVALID_MODE", "Invalid mode");
+                };
+
+                info!("COMPARING {} AND {}", id_a, id_b);
+
+                let mut renders = Vec::new();
+                for id in [id_a, id_b] {
+                    let (bodycolors, accessories) = match fetch_avatar_render(&state.rt, pool, &state.denied_items, id) {
+                        Ok(data) => data,
+                        Err(e) => return e.response(),
                     };
 
-                    let type_val = match body.job_type.parse::<i32>() {
-                        Ok(i) => i,
-                        Err(_) => return rouille::Response::text("Invalid Number").with_status_code(400),
+                    let job = RenderJob {
+                        accessories,
+                        bodycolors: Some(bodycolors),
+                        hex_colors: None,
+                        job_type: 1,
+                        options: SceneOptions::default(),
+                        output: OutputOptions::default(),
+                        request_time: current_time,
                     };
 
-                    let id_val = match body.id.parse::<i32>() {
-                        Ok(i) => i,
-                        Err(_) => return rouille::Response::text("Invalid Number").with_status_code(400),
+                    let result = match state.submit_render(job, None) {
+                        Ok(result) => result,
+                        Err(e) => return e.response(id, 1),
+                    };
+                    let Some(image) = decode_png_base64(&result.result) else {
+                        return ServerError::RenderFailed("render didn't decode".into()).response();
                     };
+                    renders.push(image);
+                }
 
-                    let (tx_answer, rx_answer) = channel();
-                    let now: DateTime<Utc> = Utc::now();
-                    println!("[{}] JOB TYPE: {}, ID: {}. REQUESTING RENDER", now.format("%d-%m-%Y %H:%M:%S"), type_val, id_val);
-
-                    match type_val {
-                        1 => {
-                            let avatar_result = rt.block_on(async {
-                                fetch_avatar(&pool, id_val).await
-                            });
-
-                            let (bodycolors, accessory_ids) = match avatar_result {
-                                Ok(data) => data,
-                                Err(e) => {
-                                    eprintln!("DB Error for user {}: {}", id_val, e);
-                                    return rouille::Response::text("User not found").with_status_code(404);
-                                }
-                            };
-
-                            let accessories = rt.block_on(async {
-                                match fetch_accessories_info(&pool, accessory_ids).await {
-                                    Ok(a) => a,
-                                    Err(e) => {
-                                        eprintln!("Failed to fetch accessories for user {}: {}", id_val, e);
-                                        Vec::new()
-                                    }
-                                }
-                            });
-
-                            let req = RenderRequest {
-                                accessories,
-                                bodycolors: Some(bodycolors),
-                                job_type: 1,
-                                response_sender: tx_answer,
-                                request_time: current_time
-                            };
-
-                            if tx_work.send(req).is_err() {
-                                return rouille::Response::text("Fatal error, server shutting down.").with_status_code(500);
-                            }
+                let Some(combined) = encode_png_base64(&mode.combine(&renders[0], &renders[1]), &[]) else {
+                    return ServerError::RenderFailed("comparison didn't encode".into()).response();
+                };
+                render_response(request, combined, None, false, false, OutputFormat::Png, current_time)
+            },
+            (POST) (/batch) => {
+                handle_batch(&state, request)
+            },
+            (POST) (/resolve) => {
+                // What a render of this avatar would be fed, for debugging one that looks wrong.
+                // Admin only, it lists every equipped asset path.
+                if !admin_authorized(&admin_token, request) {
+                    return error_response(401, "UNAUTHORIZED", "Missing or wrong admin token");
+                }
+                let Some(pool) = &state.pool else {
+                    return error_response(503, "DATABASE_DISABLED", "Database disabled");
+                };
+                let body = match post_input!(request, { id: String }) {
+                    Ok(d) => d,
+                    Err(_) => return error_response(400, "INVALID_BODY", "Missing id or malformed form body"),
+                };
+                let Some(id) = parse_id(&body.id) else {
+                    return error_response(400, "INVALID_ID", "Invalid id");
+                };
 
-                            match rx_answer.recv() {
-                                Ok(base64_img) if !base64_img.is_empty() => rouille::Response::text(base64_img),
-                                _ => rouille::Response::text("Render Failed").with_status_code(500),
-                            }
-                        },
-                        2 => {
-                            let accessories = rt.block_on(async {
-                                match fetch_accessories_info(&pool, vec![id_val]).await {
-                                    Ok(a) => a,
-                                    Err(e) => {
-                                        eprintln!("Failed to fetch accessories for user {}: {}", id_val, e);
-                                        Vec::new()
-                                    }
-                                }
-                            });
-
-                            let req = RenderRequest {
-                                accessories,
-                                bodycolors: None,
-                                job_type: 2,
-                                response_sender: tx_answer,
-                                request_time: current_time
-                            };
-
-                            if tx_work.send(req).is_err() {
-                                return rouille::Response::text("Fatal error, server shutting down.").with_status_code(500);
-                            }
+                info!("RESOLVING {}", id);
 
-                            match rx_answer.recv() {
-                                Ok(base64_img) if !base64_img.is_empty() => rouille::Response::text(base64_img),
-                                _ => rouille::Response::text("Render Failed").with_status_code(500),
-                            }
-                        },
-                        _ => {
-                            println!("they just tried requesting a bunch of hippy dippy baloney");
-                            rouille::Response::text("Invalid job type").with_status_code(400)
-                        }
-                    }
+                let (bodycolors, accessories) = match fetch_avatar_render(&state.rt, pool, &state.denied_items, id) {
+                    Ok(data) => data,
+                    Err(e) => return e.response(),
+                };
+                // Unknown ids were already swapped for Medium stone grey (194) by fetch_avatar.
+                let color = |id: u16| serde_json::json!({
+                    "brickcolor": id,
+                    "hex": format!("#{:06X}", from_brickcolor_or_default(id)),
+                });
 
-                },
-                _ => rouille::Response::empty_404()
-            )
-        });
+                rouille::Response::json(&serde_json::json!({
+                    "id": id,
+                    "colors": {
+                        "head": color(bodycolors.head),
+                        "trso": color(bodycolors.trso),
+                        "larm": color(bodycolors.larm),
+                        "rarm": color(bodycolors.rarm),
+                        "lleg": color(bodycolors.lleg),
+                        "rleg": color(bodycolors.rleg),
+                    },
+                    "accessories": accessories,
+                }))
+            },
+            (POST) (/inline) => {
+                let current_time = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs_f64();
+
+                let spec: InlineSpec = match rouille::input::json_input(request) {
+                    Ok(spec) => spec,
+                    Err(e) => return error_response(400, "INVALID_SPEC", format!("Invalid spec: {}", e)),
+                };
+                let (colors, accessories) = match spec.resolve() {
+                    Ok(resolved) => resolved,
+                    Err(e) => return error_response(400, "INVALID_SPEC", e),
+                };
+
+                info!("INLINE RENDER, {} ACCESSORIES", accessories.len());
+
+                let job = RenderJob {
+                    accessories,
+                    bodycolors: None,
+                    hex_colors: Some(colors),
+                    job_type: 1,
+                    options: SceneOptions::default(),
+                    output: OutputOptions::default(),
+                    request_time: current_time,
+                };
+
+                let result = match state.submit_render(job, None) {
+                    Ok(result) => result,
+                    // Inline specs have no id.
+                    Err(e) => return e.response(0, 1),
+                };
+
+                render_response(request, result.result, Some(result.accessories_drawn), false, false, OutputFormat::Png, current_time)
+            },
+            (POST) (/preview) => {
+                let current_time = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs_f64();
+
+                // Checked against the declared length before anything is buffered. Uploads
+                // without one are refused rather than read in full to find out how big they are.
+                let Some(declared) = body_length(request) else {
+                    return error_response(411, "LENGTH_REQUIRED", "Content-Length is required");
+                };
+                if declared > state.preview_max_bytes {
+                    return error_response(
+                        413,
+                        "PAYLOAD_TOO_LARGE",
+                        format!("Uploads are limited to {} bytes", state.preview_max_bytes),
+                    );
+                }
+
+                let body = match post_input!(request, {
+                    image: rouille::input::post::BufferedFile,
+                    item_type: String,
+                    head_color: Option<String>,
+                    trso_color: Option<String>,
+                    larm_color: Option<String>,
+                    rarm_color: Option<String>,
+                    lleg_color: Option<String>,
+                    rleg_color: Option<String>,
+                }) {
+                    Ok(d) => d,
+                    Err(_) => return error_response(400, "INVALID_BODY", "Missing image or item_type"),
+                };
+                // The image based types, same as inline specs take data for.
+                let item_type = match body.item_type.parse::<i8>() {
+                    Ok(item_type) if (4..=7).contains(&item_type) => item_type,
+                    _ => return error_response(400, "INVALID_ITEM_TYPE", "Invalid item_type"),
+                };
+                if let Err(e) = image::load_from_memory(&body.image.data) {
+                    return ServerError::ImageDecode(e.to_string()).response();
+                }
+
+                let params = RenderParams {
+                    head_color: body.head_color,
+                    trso_color: body.trso_color,
+                    larm_color: body.larm_color,
+                    rarm_color: body.rarm_color,
+                    lleg_color: body.lleg_color,
+                    rleg_color: body.rleg_color,
+                    ..RenderParams::default()
+                };
+                let mut bodycolors = BodyColors::default();
+                match BodyColorOverrides::parse(&params) {
+                    Ok(overrides) => overrides.apply(&mut bodycolors),
+                    Err(field) => return error_response(400, "INVALID_COLOR", format!("Invalid {}", field)),
+                }
+
+                info!("PREVIEW RENDER, ITEM TYPE {}", item_type);
+
+                // The upload goes in as inline data, like in an inline spec, so it never hits
+                // the disk or the database.
+                let job = RenderJob {
+                    accessories: vec![ItemAsset {
+                        id: 0,
+                        item_type,
+                        location: None,
+                        texture_path: None,
+                        scale: None,
+                        data: Some(base64::engine::general_purpose::STANDARD.encode(&body.image.data)),
+                    }],
+                    bodycolors: Some(bodycolors),
+                    hex_colors: None,
+                    job_type: 1,
+                    options: SceneOptions::default(),
+                    output: OutputOptions::default(),
+                    request_time: current_time,
+                };
+
+                let result = match state.submit_render(job, None) {
+                    Ok(result) => result,
+                    Err(e) => return e.response(0, 1),
+                };
+
+                render_response(request, result.result, Some(result.accessories_drawn), false, false, OutputFormat::Png, current_time)
+            },
+            _ => rouille::Response::empty_404()
+        )
+    };
+
+    let tls = match (
+        env::var("TLS_CERT").ok().filter(|v| !v.is_empty()),
+        env::var("TLS_KEY").ok().filter(|v| !v.is_empty()),
+    ) {
+        (Some(cert_path), Some(key_path)) => Some((
+            std::fs::read(&cert_path).expect("Failed to read TLS_CERT"),
+            std::fs::read(&key_path).expect("Failed to read TLS_KEY"),
+        )),
+        (None, None) => None,
+        // Half a TLS setup is a mistake, not a request for plain HTTP.
+        _ => panic!("TLS_CERT and TLS_KEY must be set together"),
+    };
+
+    let address = listen_address();
+    info!(
+        "STARTED SERVER ON {}{}{}",
+        address,
+        if address.port() == DEFAULT_PORT {
+            " (unfunny)"
+        } else {
+            ""
+        },
+        if tls.is_some() { " WITH TLS" } else { "" }
+    );
+
+    match tls {
+        Some((certificate, private_key)) => {
+            rouille::Server::new_ssl(address, handler, certificate, private_key)
+                .expect("Failed to start HTTPS server")
+                .run();
+        }
+        None => rouille::start_server(address, handler),
+    }
+}
+
+// Hands jobs on to the render loop one at a time, loading the assets of the next one while the
+// current one renders so the GPU isn't left waiting on disk. The database was already queried
+// by the handler that queued the job.
+fn prefetch_jobs(rx_work: Receiver<RenderRequest>) -> Receiver<RenderRequest> {
+    // No buffer: the thread holds on to the job it prefetched until the render loop takes it.
+    let (tx_ready, rx_ready) = sync_channel::<RenderRequest>(0);
+    thread::spawn(move || {
+        let assets = AssetPaths::from_env();
+        let max_accessories = MAX_ACCESSORIES
+            .get()
+            .copied()
+            .unwrap_or(DEFAULT_MAX_ACCESSORIES);
+        for request in rx_work {
+            let job = &request.job;
+            for accessory in job.accessories.iter().take(max_accessories) {
+                if accessory.item_type == 7 && !job.options.show_face {
+                    continue;
+                }
+                prefetch_item(&assets, accessory);
+            }
+            if tx_ready.send(request).is_err() {
+                break;
+            }
+        }
     });
+    rx_ready
+}
 
+async fn render_loop(rx_work: Receiver<RenderRequest>) {
+    let rx_work = prefetch_jobs(rx_work);
+    let mut renderer = Renderer::new();
+    let render_timeout = render_timeout();
     let mut last_request_time: f64;
     loop {
-        if let Ok(work) = rx_work.try_recv() {
+        if let Ok(RenderRequest {
+            job: work,
+            response_sender,
+        }) = rx_work.try_recv()
+        {
+            QUEUE_DEPTH.fetch_sub(1, Ordering::Relaxed);
+            let waited = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs_f64()
+                - work.request_time;
+            // Whoever asked has already been sent a 504.
+            if waited > render_timeout.as_secs_f64() {
+                warn!("DROPPED JOB QUEUED FOR {}s", waited);
+                continue;
+            }
+
             match work.job_type {
-                1 => {
+                // Avatars, outfit templates and headshots only differ in where the data came
+                // from and where the camera points.
+                1 | 3 | 4 => {
                     let body_colors = work.bodycolors.unwrap_or_default();
-                    let hex_body_colors: HexBodyColors = HexBodyColors {
-                        head: from_brickcolor(body_colors.head).unwrap_or_default(),
-                        trso: from_brickcolor(body_colors.trso).unwrap_or_default(),
-                        larm: from_brickcolor(body_colors.larm).unwrap_or_default(),
-                        rarm: from_brickcolor(body_colors.rarm).unwrap_or_default(),
-                        lleg: from_brickcolor(body_colors.lleg).unwrap_or_default(),
-                        rleg: from_brickcolor(body_colors.rleg).unwrap_or_default(),
+                    let hex_body_colors: HexBodyColors = match work.hex_colors {
+                        Some(colors) => colors,
+                        None => HexBodyColors {
+                            head: from_brickcolor_or_default(body_colors.head),
+                            trso: from_brickcolor_or_default(body_colors.trso),
+                            larm: from_brickcolor_or_default(body_colors.larm),
+                            rarm: from_brickcolor_or_default(body_colors.rarm),
+                            lleg: from_brickcolor_or_default(body_colors.lleg),
+                            rleg: from_brickcolor_or_default(body_colors.rleg),
+                        },
                     };
-                    let result_b64 =
-                        render_scene(work.accessories, hex_body_colors, &static_meshes);
-                    let now: DateTime<Utc> = Utc::now();
-                    println!("[{}] SUCCESS", now.format("%d-%m-%Y %H:%M:%S"));
-                    let now: DateTime<Utc> = Utc::now();
-                    println!("[{}] SENDING...", now.format("%d-%m-%Y %H:%M:%S"));
-                    let _ = work.response_sender.send(result_b64);
-                }
-                2 => {
-                    let accessory: ItemAsset = match work.accessories.first() {
-                        Some(a) => a.clone(),
-                        None => {
-                            eprintln!(
-                                "What the fuck? ok this should never happen this is so fucking weird bro HELPPPPPPP I'M GONNA EXPLODE!!!"
-                            );
-                            unreachable!();
-                        }
+                    let framing = if work.job_type == 4 {
+                        CameraFraming::HEADSHOT
+                    } else {
+                        CameraFraming::FULL_BODY
                     };
+                    let output = render_scene(
+                        &mut renderer,
+                        work.accessories,
+                        hex_body_colors,
+                        &work.options,
+                        &work.output,
+                        &framing,
+                    );
+                    info!("SUCCESS");
+                    debug!("SENDING...");
+                    let _ = response_sender.send(output);
+                }
+                // A single item, or a bundle of them, on a plain gray body.
+                2 | 5 => {
+                    // The handler answers 404 before queueing a missing item, but a bad job
+                    // still mustn't take the render loop down.
+                    if work.accessories.is_empty() {
+                        error!("ITEM RENDER WITHOUT AN ITEM, SKIPPED");
+                        let _ = response_sender.send(Err(ServerError::RenderFailed(
+                            "item render without an item".into(),
+                        )));
+                        continue;
+                    }
+                    let mut accessories = work.accessories;
+                    if work.job_type == 2 {
+                        accessories.truncate(1);
+                    }
 
                     let colors: HexBodyColors = HexBodyColors {
                         trso: 0xbfbfbf,
@@ -457,15 +3171,25 @@ async fn main() {
                         rarm: 0xbfbfbf,
                         rleg: 0xbfbfbf,
                     };
-                    let result_b64 = render_scene(vec![accessory], colors, &static_meshes);
-                    let now: DateTime<Utc> = Utc::now();
-                    println!("[{}] SUCCESS", now.format("%d-%m-%Y %H:%M:%S"));
-                    let now: DateTime<Utc> = Utc::now();
-                    println!("[{}] SENDING...", now.format("%d-%m-%Y %H:%M:%S"));
-                    let _ = work.response_sender.send(result_b64);
+                    let output = render_scene(
+                        &mut renderer,
+                        accessories,
+                        colors,
+                        &work.options,
+                        &work.output,
+                        &CameraFraming::FULL_BODY,
+                    );
+                    info!("SUCCESS");
+                    debug!("SENDING...");
+                    let _ = response_sender.send(output);
                 }
-                _ => {
-                    unreachable!()
+                other => {
+                    error!("UNKNOWN JOB TYPE {}, SKIPPED", other);
+                    let _ = response_sender.send(Err(ServerError::RenderFailed(format!(
+                        "unknown job type {}",
+                        other
+                    ))));
+                    continue;
                 }
             }
 
@@ -474,21 +3198,17 @@ async fn main() {
                 .unwrap()
                 .as_secs_f64();
             last_request_time = current_time - work.request_time;
-            let now: DateTime<Utc> = Utc::now();
-            println!(
-                "[{}] FINISHED -- TOOK {}s.",
-                now.format("%d-%m-%Y %H:%M:%S"),
-                last_request_time
-            );
+            info!("FINISHED -- TOOK {}s.", last_request_time);
         }
 
         set_default_camera();
         clear_background(BLACK);
 
+        draw_text("Listening for requests", 10.0, 16.0, 24.0, WHITE);
         draw_text(
-            "Listening for requests",
+            &format!("Queued: {}", QUEUE_DEPTH.load(Ordering::Relaxed)),
             10.0,
-            16.0,
+            40.0,
             24.0,
             WHITE,
         );
@@ -496,3 +3216,33 @@ async fn main() {
         next_frame().await;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_head_swap_falls_back_to_the_default_head() {
+        let base = env::temp_dir().join(format!("lsd-head-swap-{}", std::process::id()));
+        std::fs::create_dir_all(&base).unwrap();
+        std::fs::write(
+            base.join("swap.obj"),
+            "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\n",
+        )
+        .unwrap();
+        let assets = AssetPaths::new(base.to_str().unwrap(), HashSet::new());
+        let default = load_static_mesh_from_bytes("default", DEFAULT_MESH_BYTES);
+        let mut head = Head::default(&default, 0x123456);
+
+        assert!(head.swap(&assets, "swap.obj", &default, 0x123456));
+        assert_eq!(head.meshes[0].positions.len(), 9);
+        assert_eq!(head.face_fill, 0xFFFFFF);
+
+        assert!(!head.swap(&assets, "missing.obj", &default, 0x123456));
+        assert!(!head.swapped);
+        assert_eq!(head.face_fill, 0x123456);
+        assert_eq!(head.meshes[0].positions, default[0].positions);
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
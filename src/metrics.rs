@@ -0,0 +1,157 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// Upper bounds of the render duration histogram, in seconds.
+const DURATION_BUCKETS: [f64; 8] = [0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+// Job types are small numbers; anything past this is counted under the last slot.
+const JOB_TYPE_SLOTS: usize = 8;
+
+// Counters behind GET /metrics, served in the Prometheus text format. They're kept by the
+// front-end as results come back from the render queue, so renders done by --workers processes
+// count too, and durations include the time spent queued.
+#[derive(Default)]
+pub struct Metrics {
+    renders: [AtomicU64; JOB_TYPE_SLOTS],
+    failures: AtomicU64,
+    // Per bucket, not cumulative; the last one is past the largest bound.
+    durations: [AtomicU64; DURATION_BUCKETS.len() + 1],
+    duration_micros: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+}
+
+impl Metrics {
+    pub fn cache_hit(&self) {
+        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn cache_miss(&self) {
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // A render the loop answered, `ok` false when it failed.
+    pub fn render_done(&self, job_type: u8, elapsed: Duration, ok: bool) {
+        let slot = (job_type as usize).min(JOB_TYPE_SLOTS - 1);
+        self.renders[slot].fetch_add(1, Ordering::Relaxed);
+        if !ok {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let secs = elapsed.as_secs_f64();
+        let bucket = DURATION_BUCKETS
+            .iter()
+            .position(|bound| secs <= *bound)
+            .unwrap_or(DURATION_BUCKETS.len());
+        self.durations[bucket].fetch_add(1, Ordering::Relaxed);
+        self.duration_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn render_text(&self, queue_depth: usize, peak_memory_kb: Option<u64>) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "lsd_renders_total",
+            "counter",
+            "Renders answered by the render loop, failed ones included.",
+        );
+        for (job_type, count) in self.renders.iter().enumerate() {
+            let count = count.load(Ordering::Relaxed);
+            if count > 0 {
+                let _ = writeln!(
+                    out,
+                    "lsd_renders_total{{job_type=\"{}\"}} {}",
+                    job_type, count
+                );
+            }
+        }
+
+        header(
+            &mut out,
+            "lsd_render_failures_total",
+            "counter",
+            "Renders that failed.",
+        );
+        let _ = writeln!(
+            out,
+            "lsd_render_failures_total {}",
+            self.failures.load(Ordering::Relaxed)
+        );
+
+        header(
+            &mut out,
+            "lsd_render_duration_seconds",
+            "histogram",
+            "Time from queueing a render to its result, queue wait included.",
+        );
+        let mut cumulative = 0;
+        for (i, count) in self.durations.iter().enumerate() {
+            cumulative += count.load(Ordering::Relaxed);
+            let le = DURATION_BUCKETS
+                .get(i)
+                .map_or("+Inf".to_owned(), |bound| bound.to_string());
+            let _ = writeln!(
+                out,
+                "lsd_render_duration_seconds_bucket{{le=\"{}\"}} {}",
+                le, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "lsd_render_duration_seconds_sum {}",
+            self.duration_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+        );
+        let _ = writeln!(out, "lsd_render_duration_seconds_count {}", cumulative);
+
+        header(
+            &mut out,
+            "lsd_cache_hits_total",
+            "counter",
+            "Renders answered from the render cache.",
+        );
+        let _ = writeln!(
+            out,
+            "lsd_cache_hits_total {}",
+            self.cache_hits.load(Ordering::Relaxed)
+        );
+        header(
+            &mut out,
+            "lsd_cache_misses_total",
+            "counter",
+            "Renders the cache didn't have, including ones that joined an identical render.",
+        );
+        let _ = writeln!(
+            out,
+            "lsd_cache_misses_total {}",
+            self.cache_misses.load(Ordering::Relaxed)
+        );
+
+        header(
+            &mut out,
+            "lsd_queue_depth",
+            "gauge",
+            "Jobs waiting in the render queue.",
+        );
+        let _ = writeln!(out, "lsd_queue_depth {}", queue_depth);
+
+        // Not there where /proc isn't.
+        if let Some(kb) = peak_memory_kb {
+            header(
+                &mut out,
+                "lsd_peak_memory_bytes",
+                "gauge",
+                "Peak resident memory of the server process.",
+            );
+            let _ = writeln!(out, "lsd_peak_memory_bytes {}", kb * 1024);
+        }
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
@@ -0,0 +1,138 @@
+use macroquad::prelude::*;
+use serde::{Deserialize, Serialize};
+
+// What a capture writes to the color buffer. Normals and depth are for clients that relight
+// thumbnails themselves.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum RenderPass {
+    #[default]
+    Color,
+    // World-space normal mapped from -1..1 to 0..255 per channel.
+    Normals,
+    // Linear distance from the camera as gray, black at DepthRange.x and white at DepthRange.y.
+    Depth,
+}
+
+impl RenderPass {
+    pub fn from_name(name: &str) -> Option<RenderPass> {
+        match name {
+            "color" => Some(RenderPass::Color),
+            "normals" => Some(RenderPass::Normals),
+            "depth" => Some(RenderPass::Depth),
+            _ => None,
+        }
+    }
+}
+
+// Same attributes and uniforms as macroquad's own 3D shader, so draw_mesh feeds it unchanged.
+const VERTEX: &str = r#"#version 100
+attribute vec3 position;
+attribute vec2 texcoord;
+attribute vec4 color0;
+attribute vec4 normal;
+
+varying lowp vec2 uv;
+varying lowp float alpha;
+varying mediump vec3 world_normal;
+varying mediump float view_depth;
+
+uniform mat4 Model;
+uniform mat4 Projection;
+
+void main() {
+    gl_Position = Projection * Model * vec4(position, 1);
+    uv = texcoord;
+    alpha = color0.a / 255.0;
+    world_normal = normal.xyz;
+    view_depth = gl_Position.w;
+}"#;
+
+const NORMALS_FRAGMENT: &str = r#"#version 100
+precision mediump float;
+varying lowp vec2 uv;
+varying lowp float alpha;
+varying mediump vec3 world_normal;
+
+uniform sampler2D Texture;
+
+void main() {
+    if (alpha * texture2D(Texture, uv).a < 0.5) {
+        discard;
+    }
+    gl_FragColor = vec4(normalize(world_normal) * 0.5 + 0.5, 1.0);
+}"#;
+
+const DEPTH_FRAGMENT: &str = r#"#version 100
+precision mediump float;
+varying lowp vec2 uv;
+varying lowp float alpha;
+varying mediump float view_depth;
+
+uniform sampler2D Texture;
+uniform vec2 DepthRange;
+
+void main() {
+    if (alpha * texture2D(Texture, uv).a < 0.5) {
+        discard;
+    }
+    float depth = clamp((view_depth - DepthRange.x) / (DepthRange.y - DepthRange.x), 0.0, 1.0);
+    gl_FragColor = vec4(vec3(depth), 1.0);
+}"#;
+
+pub struct PassMaterials {
+    normals: Material,
+    depth: Material,
+}
+
+impl PassMaterials {
+    pub fn load() -> PassMaterials {
+        // No blending: the pass writes data, not color, and the clear stays transparent.
+        let pipeline_params = PipelineParams {
+            depth_write: true,
+            depth_test: Comparison::LessOrEqual,
+            ..Default::default()
+        };
+
+        let normals = load_material(
+            ShaderSource::Glsl {
+                vertex: VERTEX,
+                fragment: NORMALS_FRAGMENT,
+            },
+            MaterialParams {
+                pipeline_params,
+                ..Default::default()
+            },
+        )
+        .expect("Failed to compile normals shader");
+
+        let depth = load_material(
+            ShaderSource::Glsl {
+                vertex: VERTEX,
+                fragment: DEPTH_FRAGMENT,
+            },
+            MaterialParams {
+                pipeline_params,
+                uniforms: vec![UniformDesc::new("DepthRange", UniformType::Float2)],
+                ..Default::default()
+            },
+        )
+        .expect("Failed to compile depth shader");
+
+        PassMaterials { normals, depth }
+    }
+
+    // Switches to the material for `pass`. The depth range is centered on the camera target,
+    // wide enough for a whole avatar with hats.
+    pub fn apply(&self, pass: RenderPass, camera_distance: f32) {
+        match pass {
+            RenderPass::Color => gl_use_default_material(),
+            RenderPass::Normals => gl_use_material(&self.normals),
+            RenderPass::Depth => {
+                let near = (camera_distance - 5.0).max(0.0);
+                self.depth
+                    .set_uniform("DepthRange", vec2(near, camera_distance + 5.0));
+                gl_use_material(&self.depth);
+            }
+        }
+    }
+}
@@ -0,0 +1,298 @@
+use base64::Engine;
+use log::{error, info, warn};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use std::{env, fs, io, thread};
+
+const DEFAULT_PATH_TEMPLATE: &str = "{job_type}/{id}/{view}.{ext}";
+const DEFAULT_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Where finished renders get written when OUTPUT_DIR is set. The file name comes from
+// OUTPUT_PATH_TEMPLATE, which can use {id}, {id_prefix} (id / 1000, for sharding),
+// {job_type}, {view}, {size}, {hash} and {ext} (png or jpg). OUTPUT_DIR_MAX_BYTES and OUTPUT_DIR_MAX_FILES cap
+// the directory; a background thread evicts the least recently written files past either cap.
+// The same thread deletes files older than OUTPUT_DIR_TTL_SECS, every
+// OUTPUT_DIR_PRUNE_INTERVAL_SECS.
+#[derive(Clone)]
+pub struct OutputDir {
+    root: PathBuf,
+    template: String,
+    max_bytes: Option<u64>,
+    max_files: Option<usize>,
+    ttl: Option<Duration>,
+    prune_interval: Duration,
+}
+
+pub struct RenderInfo {
+    pub id: i32,
+    pub job_type: i32,
+    pub size: u32,
+    pub hash: u64,
+    pub ext: &'static str,
+    // What a single image result is stored as; JSON results name their own views.
+    pub view: &'static str,
+}
+
+impl OutputDir {
+    pub fn from_env() -> Option<OutputDir> {
+        let root = env::var("OUTPUT_DIR").ok().filter(|d| !d.is_empty())?;
+        let template = env::var("OUTPUT_PATH_TEMPLATE")
+            .ok()
+            .filter(|t| !t.is_empty())
+            .unwrap_or_else(|| DEFAULT_PATH_TEMPLATE.to_owned());
+
+        Some(OutputDir {
+            root: PathBuf::from(root),
+            template,
+            max_bytes: env_limit("OUTPUT_DIR_MAX_BYTES"),
+            max_files: env_limit("OUTPUT_DIR_MAX_FILES"),
+            ttl: env_limit("OUTPUT_DIR_TTL_SECS").map(Duration::from_secs),
+            prune_interval: env_limit("OUTPUT_DIR_PRUNE_INTERVAL_SECS")
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_PRUNE_INTERVAL),
+        })
+    }
+
+    pub fn spawn_pruner(&self) {
+        if self.max_bytes.is_none() && self.max_files.is_none() && self.ttl.is_none() {
+            return;
+        }
+
+        let dir = self.clone();
+        thread::spawn(move || {
+            loop {
+                dir.prune();
+                thread::sleep(dir.prune_interval);
+            }
+        });
+    }
+
+    fn prune(&self) {
+        let mut files = Vec::new();
+        collect_files(&self.root, &mut files);
+        // Oldest first, those go before anything else.
+        files.sort_by_key(|file| file.modified);
+
+        if let Some(ttl) = self.ttl {
+            let cutoff = SystemTime::now()
+                .checked_sub(ttl)
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            let expired = files.partition_point(|file| file.modified < cutoff);
+            let (mut reaped, mut freed) = (0, 0);
+            for file in files.drain(..expired) {
+                if self.remove(&file) {
+                    reaped += 1;
+                    freed += file.len;
+                }
+            }
+            // Every pass is logged, empty ones only at debug level.
+            let level = if reaped > 0 {
+                log::Level::Info
+            } else {
+                log::Level::Debug
+            };
+            log::log!(
+                level,
+                "Reaped {} renders ({} bytes) older than {}s from OUTPUT_DIR",
+                reaped,
+                freed,
+                ttl.as_secs()
+            );
+        }
+
+        let mut total_bytes: u64 = files.iter().map(|file| file.len).sum();
+        let mut total_files = files.len();
+        let (mut evicted, mut freed) = (0, 0);
+
+        for file in &files {
+            let over_bytes = self.max_bytes.is_some_and(|max| total_bytes > max);
+            let over_files = self.max_files.is_some_and(|max| total_files > max);
+            if !over_bytes && !over_files {
+                break;
+            }
+
+            if !self.remove(file) {
+                continue;
+            }
+
+            total_bytes -= file.len;
+            total_files -= 1;
+            evicted += 1;
+            freed += file.len;
+        }
+
+        if evicted > 0 {
+            info!(
+                "Evicted {} renders ({} bytes) from OUTPUT_DIR, {} files / {} bytes left",
+                evicted, freed, total_files, total_bytes
+            );
+        }
+    }
+
+    fn remove(&self, file: &StoredFile) -> bool {
+        if let Err(e) = fs::remove_file(&file.path) {
+            error!("Failed to evict {}: {}", file.path.display(), e);
+            return false;
+        }
+        // Only succeeds once the render's folder is empty.
+        if let Some(parent) = file.path.parent()
+            && parent != self.root
+        {
+            let _ = fs::remove_dir(parent);
+        }
+        true
+    }
+
+    pub fn path_for(&self, info: &RenderInfo, view: &str) -> Option<PathBuf> {
+        Some(self.root.join(self.relative_path_for(info, view)?))
+    }
+
+    fn relative_path_for(&self, info: &RenderInfo, view: &str) -> Option<String> {
+        let relative = expand_template(&self.template, info, view);
+        // Only plain components, so a template can't climb out of OUTPUT_DIR.
+        if !Path::new(&relative)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+        {
+            return None;
+        }
+        Some(relative)
+    }
+
+    // Paths (relative to OUTPUT_DIR) of every view if all of them are already written. Only
+    // a template with {hash} in it tells renders of the same id apart, without it a file on
+    // disk may be from before the avatar changed.
+    pub fn existing(&self, info: &RenderInfo, views: &[&str]) -> Option<Vec<(String, String)>> {
+        if !self.template.contains("{hash}") {
+            return None;
+        }
+        views
+            .iter()
+            .map(|view| {
+                let relative = self.relative_path_for(info, view)?;
+                self.root
+                    .join(&relative)
+                    .is_file()
+                    .then(|| (view.to_string(), relative))
+            })
+            .collect()
+    }
+
+    pub fn write(&self, info: &RenderInfo, view: &str, data: &[u8]) -> io::Result<PathBuf> {
+        let path = self.path_for(info, view).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "OUTPUT_PATH_TEMPLATE expands outside OUTPUT_DIR",
+            )
+        })?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&path, data)?;
+        Ok(path)
+    }
+
+    // Takes the handler's render result (a base64 image or a JSON object of them) and writes
+    // every image in it. Returns the views written with their paths relative to OUTPUT_DIR.
+    pub fn write_result(&self, info: &RenderInfo, result: &str) -> Vec<(String, String)> {
+        let mut written = Vec::new();
+        for (view, b64) in result_views(info, result) {
+            let data = match base64::engine::general_purpose::STANDARD.decode(&b64) {
+                Ok(data) => data,
+                Err(e) => {
+                    warn!("Not persisting {} view of {}: {}", view, info.id, e);
+                    continue;
+                }
+            };
+            match self.write(info, &view, &data) {
+                Ok(path) => {
+                    let relative = path.strip_prefix(&self.root).unwrap_or(&path);
+                    written.push((view, relative.to_string_lossy().into_owned()));
+                }
+                Err(e) => error!("Failed to persist {} view of {}: {}", view, info.id, e),
+            }
+        }
+        written
+    }
+
+    // The views of a result that are on disk, with their paths relative to OUTPUT_DIR, for
+    // requests that got it from a render someone else wrote.
+    pub fn result_paths(&self, info: &RenderInfo, result: &str) -> Vec<(String, String)> {
+        result_views(info, result)
+            .into_iter()
+            .filter_map(|(view, _)| {
+                let relative = self.relative_path_for(info, &view)?;
+                self.root
+                    .join(&relative)
+                    .is_file()
+                    .then_some((view, relative))
+            })
+            .collect()
+    }
+}
+
+// Every image in a render result as (view, base64).
+fn result_views(info: &RenderInfo, result: &str) -> Vec<(String, String)> {
+    match serde_json::from_str::<serde_json::Value>(result) {
+        Ok(serde_json::Value::Object(map)) => map
+            .into_iter()
+            .filter(|(key, _)| key != "lqip")
+            .filter_map(|(key, value)| {
+                let view = if key == "image" {
+                    info.view.into()
+                } else {
+                    key
+                };
+                Some((view, value.as_str()?.to_owned()))
+            })
+            .collect(),
+        _ => vec![(info.view.into(), result.to_owned())],
+    }
+}
+
+struct StoredFile {
+    path: PathBuf,
+    len: u64,
+    modified: SystemTime,
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<StoredFile>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+
+    for entry in entries.flatten() {
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if metadata.is_dir() {
+            collect_files(&entry.path(), files);
+        } else if metadata.is_file() {
+            files.push(StoredFile {
+                path: entry.path(),
+                len: metadata.len(),
+                modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+            });
+        }
+    }
+}
+
+fn env_limit<T: std::str::FromStr>(name: &str) -> Option<T> {
+    let value = env::var(name).ok().filter(|v| !v.is_empty())?;
+    match value.parse() {
+        Ok(limit) => Some(limit),
+        Err(_) => panic!("{} must be a number", name),
+    }
+}
+
+fn expand_template(template: &str, info: &RenderInfo, view: &str) -> String {
+    template
+        .replace("{id_prefix}", &(info.id / 1000).to_string())
+        .replace("{id}", &info.id.to_string())
+        .replace("{job_type}", &info.job_type.to_string())
+        .replace("{view}", view)
+        .replace("{size}", &info.size.to_string())
+        .replace("{hash}", &format!("{:016x}", info.hash))
+        .replace("{ext}", info.ext)
+}
@@ -0,0 +1,160 @@
+use macroquad::prelude::*;
+use serde::{Deserialize, Serialize};
+
+// Joint positions in the same space as the bundled limb OBJs.
+const RARM_PIVOT: Vec3 = Vec3::new(1.5, -0.5, 0.0);
+const LARM_PIVOT: Vec3 = Vec3::new(-1.5, -0.5, 0.0);
+const RLEG_PIVOT: Vec3 = Vec3::new(0.5, -2.0, 0.0);
+const LLEG_PIVOT: Vec3 = Vec3::new(-0.5, -2.0, 0.0);
+
+// Forward/backward swing of each limb, in radians. The default is the static standing pose.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Pose {
+    pub rarm: f32,
+    pub larm: f32,
+    pub rleg: f32,
+    pub lleg: f32,
+}
+
+impl Pose {
+    fn lerp(&self, other: &Pose, t: f32) -> Pose {
+        Pose {
+            rarm: self.rarm + (other.rarm - self.rarm) * t,
+            larm: self.larm + (other.larm - self.larm) * t,
+            rleg: self.rleg + (other.rleg - self.rleg) * t,
+            lleg: self.lleg + (other.lleg - self.lleg) * t,
+        }
+    }
+
+    pub fn rarm_transform(&self) -> Mat4 {
+        swing(RARM_PIVOT, self.rarm)
+    }
+
+    pub fn larm_transform(&self) -> Mat4 {
+        swing(LARM_PIVOT, self.larm)
+    }
+
+    pub fn rleg_transform(&self) -> Mat4 {
+        swing(RLEG_PIVOT, self.rleg)
+    }
+
+    pub fn lleg_transform(&self) -> Mat4 {
+        swing(LLEG_PIVOT, self.lleg)
+    }
+}
+
+// Body shape multipliers for games with non-standard avatars, all 1.0 for the usual one. The
+// head scales around the neck and the limbs lengthen downwards from the shoulders and hips, so
+// nothing comes apart; a taller torso pushes the legs down with it.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Proportions {
+    pub head: f32,
+    pub torso_height: f32,
+    pub limb_length: f32,
+}
+
+impl Default for Proportions {
+    fn default() -> Self {
+        Proportions {
+            head: 1.0,
+            torso_height: 1.0,
+            limb_length: 1.0,
+        }
+    }
+}
+
+impl Proportions {
+    // Smallest and largest multiplier accepted for each part.
+    pub const RANGE: (f32, f32) = (0.5, 2.0);
+
+    // For the head, and everything worn on it.
+    pub fn head_scale(&self) -> Vec3 {
+        Vec3::splat(self.head)
+    }
+
+    pub fn torso_scale(&self) -> Vec3 {
+        vec3(1.0, self.torso_height, 1.0)
+    }
+
+    pub fn limb_scale(&self) -> Vec3 {
+        vec3(1.0, self.limb_length, 1.0)
+    }
+
+    // Takes a leg scaled by limb_scale back up to the hip for `swing`, then down to the
+    // bottom of the scaled torso.
+    pub fn leg_transform(&self, swing: Mat4) -> Mat4 {
+        let hip = RLEG_PIVOT.y;
+        Mat4::from_translation(vec3(0.0, hip * (self.torso_height - 1.0), 0.0))
+            * swing
+            * Mat4::from_translation(vec3(0.0, hip * (1.0 - self.limb_length), 0.0))
+    }
+}
+
+fn swing(pivot: Vec3, angle: f32) -> Mat4 {
+    Mat4::from_translation(pivot) * Mat4::from_rotation_x(angle) * Mat4::from_translation(-pivot)
+}
+
+pub struct Keyframe {
+    pub phase: f32,
+    pub pose: Pose,
+}
+
+// A looping limb animation. Keyframes are sorted by phase and span 0.0..=1.0.
+pub struct Animation {
+    keyframes: Vec<Keyframe>,
+}
+
+impl Animation {
+    pub fn from_name(name: &str) -> Option<Animation> {
+        match name {
+            "walk" => Some(Self::swing_cycle(0.6, 0.5)),
+            "run" => Some(Self::swing_cycle(1.2, 1.0)),
+            _ => None,
+        }
+    }
+
+    // Arms swing opposite to the legs on the same side, like an actual walk.
+    fn swing_cycle(arm: f32, leg: f32) -> Animation {
+        let stride = |s: f32| Pose {
+            rarm: arm * s,
+            larm: -arm * s,
+            rleg: -leg * s,
+            lleg: leg * s,
+        };
+
+        let keyframes = [
+            (0.0, 0.0),
+            (0.25, 1.0),
+            (0.5, 0.0),
+            (0.75, -1.0),
+            (1.0, 0.0),
+        ]
+        .into_iter()
+        .map(|(phase, s)| Keyframe {
+            phase,
+            pose: stride(s),
+        })
+        .collect();
+
+        Animation { keyframes }
+    }
+
+    pub fn sample(&self, phase: f32) -> Pose {
+        let phase = phase.rem_euclid(1.0);
+
+        for window in self.keyframes.windows(2) {
+            let (a, b) = (&window[0], &window[1]);
+            if phase >= a.phase && phase <= b.phase {
+                let span = b.phase - a.phase;
+                let t = if span > 0.0 {
+                    (phase - a.phase) / span
+                } else {
+                    0.0
+                };
+                return a.pose.lerp(&b.pose, t);
+            }
+        }
+
+        self.keyframes.last().map(|k| k.pose).unwrap_or_default()
+    }
+}
@@ -0,0 +1,140 @@
+// Downloads asset images off a CDN. Redirects are followed by hand so every hop can be checked
+// against ASSET_URL_HOSTS, and URLs that just failed aren't tried again for a while, so a dead
+// texture can't stall every render that uses it.
+
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+static AGENT: OnceLock<ureq::Agent> = OnceLock::new();
+// Recently failed URLs, with when they failed and why.
+static FAILURES: OnceLock<Mutex<HashMap<String, (Instant, String)>>> = OnceLock::new();
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+// Far more than any texture should be, so a bad URL can't fill up memory.
+const MAX_RESPONSE_BYTES: u64 = 32 * 1024 * 1024;
+const MAX_REDIRECTS: usize = 5;
+// How long a failed URL is answered with its last error instead of being fetched again.
+const FAILURE_TTL: Duration = Duration::from_secs(60);
+
+pub struct Url<'a> {
+    location: &'a str,
+    https: bool,
+    host: &'a str,
+    // Host as written, with the port if there was one.
+    authority: &'a str,
+}
+
+impl<'a> Url<'a> {
+    // None for anything that isn't an http:// or https:// URL, local asset paths included.
+    pub fn parse(location: &'a str) -> Option<Url<'a>> {
+        let (https, rest) = if let Some(rest) = location.strip_prefix("https://") {
+            (true, rest)
+        } else {
+            (false, location.strip_prefix("http://")?)
+        };
+
+        let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+        let host = match authority.rsplit_once(':') {
+            Some((host, port)) => {
+                port.parse::<u16>().ok()?;
+                host
+            }
+            None => authority,
+        };
+        if host.is_empty() {
+            return None;
+        }
+
+        Some(Url {
+            location,
+            https,
+            host,
+            authority,
+        })
+    }
+
+    pub fn host(&self) -> &str {
+        self.host
+    }
+
+    // Where a Location header points, relative to this URL.
+    fn join(&self, target: &str) -> String {
+        let scheme = if self.https { "https:" } else { "http:" };
+        if target.starts_with("http://") || target.starts_with("https://") {
+            target.to_owned()
+        } else if target.starts_with("//") {
+            format!("{}{}", scheme, target)
+        } else if target.starts_with('/') {
+            format!("{}//{}{}", scheme, self.authority, target)
+        } else {
+            let base = self.location.split(['?', '#']).next().unwrap_or_default();
+            match base.rfind('/') {
+                Some(i) if i > scheme.len() + 2 => format!("{}{}", &base[..=i], target),
+                _ => format!("{}//{}/{}", scheme, self.authority, target),
+            }
+        }
+    }
+}
+
+pub fn download(url: &Url, allowed_hosts: &HashSet<String>) -> Result<Vec<u8>, Box<dyn Error>> {
+    let failures = FAILURES.get_or_init(|| Mutex::new(HashMap::new()));
+    if let Ok(failures) = failures.lock()
+        && let Some((at, error)) = failures.get(url.location)
+        && at.elapsed() < FAILURE_TTL
+    {
+        return Err(format!("{} (failed recently, not retrying yet)", error).into());
+    }
+
+    let result = fetch(url, allowed_hosts);
+    if let Ok(mut failures) = failures.lock() {
+        failures.retain(|_, (at, _)| at.elapsed() < FAILURE_TTL);
+        match &result {
+            Ok(_) => {
+                failures.remove(url.location);
+            }
+            Err(e) => {
+                failures.insert(url.location.to_owned(), (Instant::now(), e.to_string()));
+            }
+        }
+    }
+    result
+}
+
+fn fetch(url: &Url, allowed_hosts: &HashSet<String>) -> Result<Vec<u8>, Box<dyn Error>> {
+    let agent = AGENT.get_or_init(|| {
+        ureq::Agent::config_builder()
+            .timeout_global(Some(TIMEOUT))
+            .max_redirects(0)
+            .build()
+            .into()
+    });
+
+    let mut location = url.location.to_owned();
+    for _ in 0..=MAX_REDIRECTS {
+        let mut response = agent.get(&location).call()?;
+        if !response.status().is_redirection() {
+            return Ok(response
+                .body_mut()
+                .with_config()
+                .limit(MAX_RESPONSE_BYTES)
+                .read_to_vec()?);
+        }
+
+        let target = response
+            .headers()
+            .get("location")
+            .and_then(|value| value.to_str().ok())
+            .ok_or("Redirect without a Location")?;
+        let next = Url::parse(&location)
+            .map(|current| current.join(target))
+            .ok_or("Redirected from a malformed URL")?;
+        match Url::parse(&next) {
+            Some(parsed) if allowed_hosts.contains(parsed.host()) => {}
+            _ => return Err(format!("Redirect to {:?} isn't on ASSET_URL_HOSTS", next).into()),
+        }
+        location = next;
+    }
+    Err("Too many redirects".into())
+}
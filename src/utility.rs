@@ -1,27 +1,197 @@
 use image::{GenericImageView, ImageError, ImageReader};
+use log::{debug, error, info, warn};
 use macroquad::prelude::*;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use sqlx::prelude::FromRow;
-use sqlx::{MySql, Pool};
+use sqlx::{MySql, Pool, QueryBuilder};
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use std::path::Path;
+use std::fs;
 use std::io::Cursor;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+
+use crate::cache::{Lru, capacity_from_env};
+use crate::error::{Missing, ServerError};
+use crate::remote::{self, Url};
+
+// Deployment specific brickcolors from BRICKCOLOR_PALETTE, consulted before the built-in table.
+static BRICKCOLOR_PALETTE: OnceLock<HashMap<u16, u32>> = OnceLock::new();
+// Whether the items table has the optional scale column, checked once at startup.
+static ITEMS_HAVE_SCALE: OnceLock<bool> = OnceLock::new();
+// Decoded asset images by path, so clothing shared by many avatars is only read and decoded
+// once. IMAGE_CACHE_MAX_BYTES bounds the RGBA data kept.
+static IMAGE_CACHE: OnceLock<Mutex<Lru<PathBuf, CachedImage>>> = OnceLock::new();
+// Parsed accessory meshes by path, so popular hats aren't read and parsed from disk on every
+// render. MESH_CACHE_SIZE sets how many files are kept.
+static MESH_CACHE: OnceLock<Mutex<Lru<PathBuf, CachedMesh>>> = OnceLock::new();
+static SRGB_TO_LINEAR: OnceLock<[f32; 256]> = OnceLock::new();
+
+const DEFAULT_IMAGE_CACHE_BYTES: usize = 256 * 1024 * 1024;
+const DEFAULT_MESH_CACHE_SIZE: usize = 256;
+const DEFAULT_COLOR_CACHE_SIZE: usize = 256;
+
+// Medium stone grey, what every id the palette doesn't know is drawn as.
+const UNKNOWN_BRICKCOLOR_ID: u16 = 194;
+const UNKNOWN_BRICKCOLOR: u32 = 0xA3A2A5;
+// Accessories without a texture, the same gray as the body in item renders.
+const UNTEXTURED_COLOR: [u8; 4] = [0xbf, 0xbf, 0xbf, 0xff];
+const DEFAULT_ASSET_BASE_PATH: &str = "/srv/http";
+const DB_ATTEMPTS: u32 = 3;
+const DB_RETRY_DELAY: Duration = Duration::from_millis(200);
+
+// Where item meshes and textures live. The items table stores paths relative to this, or for
+// textures an http(s) URL on one of ASSET_URL_HOSTS.
+pub struct AssetPaths {
+    base: String,
+    // The base with symlinks resolved, for checking where a path really ends up.
+    canonical_base: Option<PathBuf>,
+    url_hosts: HashSet<String>,
+}
+
+impl AssetPaths {
+    pub fn from_env() -> AssetPaths {
+        let base = std::env::var("ASSET_BASE_PATH")
+            .ok()
+            .filter(|p| !p.is_empty())
+            .unwrap_or_else(|| DEFAULT_ASSET_BASE_PATH.to_owned());
+        let url_hosts = std::env::var("ASSET_URL_HOSTS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|host| !host.is_empty())
+            .map(str::to_owned)
+            .collect();
+        AssetPaths::new(&base, url_hosts)
+    }
+
+    pub fn new(base: &str, url_hosts: HashSet<String>) -> AssetPaths {
+        AssetPaths {
+            canonical_base: fs::canonicalize(base).ok(),
+            base: base.trim_end_matches('/').to_owned(),
+            url_hosts,
+        }
+    }
+
+    // Decoded RGBA image from a local asset path or an allowed URL.
+    pub fn load_image(&self, location: &str) -> Result<(u32, u32, Vec<u8>), Box<dyn Error>> {
+        let Some(url) = Url::parse(location) else {
+            let path = self.resolve(location)?;
+            return Ok(process_img(Path::new(&path))?);
+        };
+
+        // Locations can come from inline specs, so only known CDNs get fetched.
+        if !self.url_hosts.contains(url.host()) {
+            warn!(
+                "Refusing asset URL {:?}, host isn't in ASSET_URL_HOSTS",
+                location
+            );
+            return Err(format!("Asset URL {:?} isn't on an allowed host", location).into());
+        }
+        remote_img(location, &url, &self.url_hosts)
+    }
+
+    // Paths come from the database, so anything that would land outside the base directory
+    // (through `..` or a symlink) is refused and logged.
+    pub fn resolve(&self, relative: &str) -> Result<String, String> {
+        let relative = relative.trim_start_matches('/');
+        if Path::new(relative)
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+            warn!("Refusing asset path {:?} outside ASSET_BASE_PATH", relative);
+            return Err(format!("Asset path {:?} escapes ASSET_BASE_PATH", relative));
+        }
+
+        let path = format!("{}/{}", self.base, relative);
+        // Missing files fail to load later with a clearer error, only existing ones can be
+        // followed through symlinks.
+        if let (Some(base), Ok(resolved)) = (&self.canonical_base, fs::canonicalize(&path))
+            && !resolved.starts_with(base)
+        {
+            warn!(
+                "Refusing asset path {:?}, it resolves to {} outside ASSET_BASE_PATH",
+                relative,
+                resolved.display()
+            );
+            return Err(format!("Asset path {:?} escapes ASSET_BASE_PATH", relative));
+        }
+        Ok(path)
+    }
+}
 
 #[derive(Debug, FromRow)]
-struct JsonString {
-    json: String,
+struct ProfileRow {
+    colors: String,
+    equipped: String,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+// Every field has to be present, but a single bad value (negative, too large, a string) only
+// spoils that field. See normalize.
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct BodyColors {
+    #[serde(deserialize_with = "lenient_brickcolor")]
     pub trso: u16,
+    #[serde(deserialize_with = "lenient_brickcolor")]
     pub head: u16,
+    #[serde(deserialize_with = "lenient_brickcolor")]
     pub lleg: u16,
+    #[serde(deserialize_with = "lenient_brickcolor")]
     pub larm: u16,
+    #[serde(deserialize_with = "lenient_brickcolor")]
     pub rarm: u16,
+    #[serde(deserialize_with = "lenient_brickcolor")]
     pub rleg: u16,
 }
 
+// Out of range or non-numeric ids become 0, which isn't a brickcolor, so normalize catches them.
+fn lenient_brickcolor<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<u16, D::Error> {
+    let value = serde_json::Value::deserialize(deserializer)?;
+    let id = match &value {
+        serde_json::Value::String(s) => s.trim().parse::<i64>().ok(),
+        _ => value.as_i64(),
+    };
+    Ok(id.and_then(|id| u16::try_from(id).ok()).unwrap_or(0))
+}
+
+impl BodyColors {
+    // The colors column of a profile or outfit. JSON that doesn't parse gives the default
+    // colors, and unknown ids are normalized.
+    fn from_column(json: &str, owner: &str) -> BodyColors {
+        let mut body_colors: BodyColors = serde_json::from_str(json).unwrap_or_else(|err| {
+            warn!("Failed to parse body colors for {}: {}", owner, err);
+            BodyColors::default()
+        });
+        body_colors.normalize(owner);
+        body_colors
+    }
+
+    // Swaps any id the palette doesn't know for Medium stone grey (194), the same color
+    // from_brickcolor_or_default draws them as, so a bad profile renders grey instead of black.
+    pub fn normalize(&mut self, owner: &str) {
+        let parts = [
+            ("head", &mut self.head),
+            ("trso", &mut self.trso),
+            ("larm", &mut self.larm),
+            ("rarm", &mut self.rarm),
+            ("lleg", &mut self.lleg),
+            ("rleg", &mut self.rleg),
+        ];
+
+        for (part, id) in parts {
+            if from_brickcolor(*id).is_none() {
+                warn!(
+                    "Unknown brickcolor {} for {} of {}, using {}",
+                    id, part, owner, UNKNOWN_BRICKCOLOR_ID
+                );
+                *id = UNKNOWN_BRICKCOLOR_ID;
+            }
+        }
+    }
+}
+
 impl Default for BodyColors {
     fn default() -> Self {
         Self {
@@ -35,155 +205,451 @@ impl Default for BodyColors {
     }
 }
 
+// Cheapest query that proves the pool can still reach the database. Bounded by `timeout` so a
+// hung connection can't hold up a health probe.
+pub async fn ping_database(pool: &Pool<MySql>, timeout: std::time::Duration) -> bool {
+    let ping = sqlx::query("SELECT 1").execute(pool);
+    matches!(tokio::time::timeout(timeout, ping).await, Ok(Ok(_)))
+}
+
+// `items.scale` isn't part of the LSD schema, so databases that haven't added it keep working
+// with every item drawn as exported. Called once after connecting.
+pub async fn detect_item_scale_column(pool: &Pool<MySql>) {
+    let found = sqlx::query_scalar::<_, i64>(
+        r#"
+        SELECT COUNT(*) FROM information_schema.columns
+        WHERE table_schema = DATABASE() AND table_name = 'items' AND column_name = 'scale'
+        "#,
+    )
+    .fetch_one(pool)
+    .await;
+    let has_scale = match found {
+        Ok(count) => count > 0,
+        Err(e) => {
+            warn!(
+                "Couldn't check for items.scale, ignoring item scales: {}",
+                e
+            );
+            false
+        }
+    };
+    if !has_scale {
+        info!("items.scale column not found, drawing items at their exported size");
+    }
+    let _ = ITEMS_HAVE_SCALE.set(has_scale);
+}
+
+// Runs a query again, waiting twice as long each time, when it failed because the connection
+// did (MySQL restarting, a dropped socket, no free connection in the pool). Anything else,
+// including a row that isn't there, comes straight back.
+async fn retry_transient<T, F, Fut>(subject: &str, mut query: F) -> Result<T, sqlx::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, sqlx::Error>>,
+{
+    let mut delay = DB_RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        match query().await {
+            Err(e) if attempt < DB_ATTEMPTS && is_transient(&e) => {
+                warn!(
+                    "Query for {} failed ({}), retrying in {}ms ({}/{})",
+                    subject,
+                    e,
+                    delay.as_millis(),
+                    attempt,
+                    DB_ATTEMPTS
+                );
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn is_transient(e: &sqlx::Error) -> bool {
+    matches!(
+        e,
+        sqlx::Error::Io(_) | sqlx::Error::Tls(_) | sqlx::Error::PoolTimedOut
+    )
+}
+
 pub async fn fetch_avatar(
     pool: &Pool<MySql>,
     user_id: i32,
-) -> Result<(BodyColors, Vec<i32>), Box<dyn Error>> {
-    let colors_row: Option<JsonString> = sqlx::query_as(
-        r#"SELECT colors as json FROM profiles WHERE id = ?"#,
-    )
-    .bind(user_id)
-    .fetch_optional(pool)
+) -> Result<(BodyColors, Vec<i32>), ServerError> {
+    let row: Option<ProfileRow> = retry_transient(&format!("user {}", user_id), || {
+        sqlx::query_as(r#"SELECT colors, equipped FROM profiles WHERE id = ?"#)
+            .bind(user_id)
+            .fetch_optional(pool)
+    })
     .await?;
 
-    let body_colors: BodyColors = match colors_row {
-        Some(row) => serde_json::from_str(&row.json).unwrap_or_else(|err| {
-            eprintln!("Failed to parse body colors for user {}: {}", user_id, err);
-            BodyColors::default()
-        }),
-        None => BodyColors::default(),
+    let Some(row) = row else {
+        return Err(ServerError::NotFound(Missing::User));
     };
 
-    let items_row: Option<JsonString> = sqlx::query_as(
-        r#"SELECT equipped as json FROM profiles WHERE id = ?"#,
-    )
-    .bind(user_id)
-    .fetch_optional(pool)
+    let body_colors = BodyColors::from_column(&row.colors, &format!("user {}", user_id));
+
+    let items: Vec<i32> = serde_json::from_str(&row.equipped).unwrap_or_else(|err| {
+        warn!("Failed to parse items for user {}: {}", user_id, err);
+        vec![0]
+    });
+
+    Ok((body_colors, items))
+}
+
+#[derive(Debug, FromRow)]
+struct OutfitRow {
+    colors: String,
+    items: String,
+}
+
+// Merch outfit templates have the same shape as a profile, but aren't tied to a user.
+pub async fn fetch_outfit(
+    pool: &Pool<MySql>,
+    outfit_id: i32,
+) -> Result<(BodyColors, Vec<i32>), ServerError> {
+    let row: Option<OutfitRow> = retry_transient(&format!("outfit {}", outfit_id), || {
+        sqlx::query_as(r#"SELECT colors, items FROM outfits WHERE id = ?"#)
+            .bind(outfit_id)
+            .fetch_optional(pool)
+    })
     .await?;
 
-    let items: Vec<i32> = match items_row {
-        Some(row) => serde_json::from_str(&row.json).unwrap_or_else(|err| {
-            eprintln!("Failed to parse items for user {}: {}", user_id, err);
-            vec![0]
-        }),
-        None => vec![0],
+    let Some(row) = row else {
+        return Err(ServerError::NotFound(Missing::Outfit));
     };
 
+    let body_colors = BodyColors::from_column(&row.colors, &format!("outfit {}", outfit_id));
+
+    let items: Vec<i32> = serde_json::from_str(&row.items).unwrap_or_else(|err| {
+        warn!("Failed to parse items for outfit {}: {}", outfit_id, err);
+        vec![0]
+    });
+
     Ok((body_colors, items))
 }
 
-#[derive(Debug, FromRow, Clone)]
+#[derive(Debug, FromRow, Clone, Serialize, Deserialize)]
 pub struct ItemAsset {
+    pub id: i32,
     pub item_type: i8,
     pub location: Option<String>,
     pub texture_path: Option<String>,
+    // Size multiplier for hat, shoulder, back and gear meshes modeled at the wrong scale.
+    // NULL (or missing) draws them as exported.
+    pub scale: Option<f32>,
+    // Base64 image from an inline spec, never selected from the database.
+    #[sqlx(default)]
+    pub data: Option<String>,
 }
 
 pub async fn fetch_accessories_info(
     pool: &Pool<MySql>,
-    item_ids: Vec<i32>,
-) -> Result<Vec<ItemAsset>, Box<dyn Error>> {
+    mut item_ids: Vec<i32>,
+    denied_items: &HashSet<i32>,
+) -> Result<Vec<ItemAsset>, ServerError> {
+    item_ids.retain(|id| {
+        let denied = denied_items.contains(id);
+        if denied {
+            info!("Item {} is on the deny list, not rendering it", id);
+        }
+        !denied
+    });
+
     if item_ids.is_empty() {
         return Ok(Vec::new());
     }
 
-    let placeholders: String = (0..item_ids.len())
-        .map(|_| "?")
-        .collect::<Vec<_>>()
-        .join(", ");
+    let item_assets: Vec<ItemAsset> = retry_transient(&format!("items {:?}", item_ids), || async {
+        let has_scale = ITEMS_HAVE_SCALE.get().copied().unwrap_or(false);
+        let mut query = accessories_query(&item_ids, has_scale);
+        query.build_query_as().fetch_all(pool).await
+    })
+    .await?;
+    Ok(item_assets)
+}
 
-    let sql = format!(
+// The ids come straight out of profile JSON, so they must only ever reach the SQL as bound
+// parameters. Build the IN list with push_bind and never format values into the string.
+fn accessories_query(item_ids: &[i32], has_scale: bool) -> QueryBuilder<'static, MySql> {
+    let scale = if has_scale { "i.scale" } else { "NULL" };
+    let mut query = QueryBuilder::new(format!(
         r#"
         SELECT
+            i.id AS id,
             i.type AS item_type,
             i.asset AS location,
-            a.asset AS texture_path
+            a.asset AS texture_path,
+            {} AS scale
         FROM items i
         LEFT JOIN items a ON i.hat_texture = a.id
-        WHERE i.id IN ({}) AND i.approved = 1
-        "#,
-        placeholders
-    );
+        WHERE i.id IN ("#,
+        scale
+    ));
 
-    let mut query = sqlx::query_as::<_, ItemAsset>(&sql);
+    let mut ids = query.separated(", ");
     for id in item_ids {
-        query = query.bind(id);
+        ids.push_bind(*id);
     }
+    ids.push_unseparated(") AND i.approved = 1");
 
-    let item_assets: Vec<ItemAsset> = query.fetch_all(pool).await?;
-    Ok(item_assets)
+    query
+}
+
+// Item ids that must never be rendered, e.g. while under moderation. Read once at startup from
+// DENY_ITEM_IDS (comma separated) and DENY_ITEM_IDS_FILE (one id per line); both may be used.
+pub fn load_denied_items() -> HashSet<i32> {
+    let mut denied = HashSet::new();
+
+    if let Ok(ids) = std::env::var("DENY_ITEM_IDS") {
+        denied.extend(parse_item_ids(&ids, "DENY_ITEM_IDS"));
+    }
+    if let Ok(path) = std::env::var("DENY_ITEM_IDS_FILE")
+        && !path.is_empty()
+    {
+        let contents = std::fs::read_to_string(&path).expect("Failed to read DENY_ITEM_IDS_FILE");
+        denied.extend(parse_item_ids(&contents, &path));
+    }
+
+    denied
+}
+
+fn parse_item_ids(list: &str, source: &str) -> Vec<i32> {
+    list.split([',', '\n'])
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .filter_map(|id| match id.parse::<i32>() {
+            Ok(id) => Some(id),
+            Err(_) => {
+                warn!("Ignoring invalid item id {:?} in {}", id, source);
+                None
+            }
+        })
+        .collect()
 }
 
 pub fn process_img(img_path: &Path) -> Result<(u32, u32, Vec<u8>), ImageError> {
+    // A changed file gets a new mtime, which misses the cache.
+    let modified = fs::metadata(img_path).and_then(|m| m.modified()).ok();
+    if let Some(hit) = cached_image(img_path, modified) {
+        return Ok(hit);
+    }
+
     let img = ImageReader::open(img_path)?.decode()?;
     let bytes = img.to_rgba8().into_vec();
     let (width, height) = img.dimensions();
+    cache_image(img_path, modified, width, height, &bytes);
+    Ok((width, height, bytes))
+}
+
+// Downloads go through the same cache as files, keyed by URL. There's no mtime to go by, so a
+// URL is only fetched again once the cache has dropped it. Redirects must stay on `url_hosts`.
+fn remote_img(
+    location: &str,
+    url: &Url,
+    url_hosts: &HashSet<String>,
+) -> Result<(u32, u32, Vec<u8>), Box<dyn Error>> {
+    let key = Path::new(location);
+    if let Some(hit) = cached_image(key, None) {
+        return Ok(hit);
+    }
+
+    let data = remote::download(url, url_hosts)
+        .map_err(|e| format!("Failed to download {}: {}", location, e))?;
+    let img = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()?
+        .decode()?;
+    let bytes = img.to_rgba8().into_vec();
+    let (width, height) = img.dimensions();
+    cache_image(key, None, width, height, &bytes);
     Ok((width, height, bytes))
 }
 
-pub fn replace_transparent_with_color(mut bytes: Vec<u8>, hex_color: u32) -> Vec<u8> {
-    let bg_r = ((hex_color >> 16) & 0xFF) as u32;
-    let bg_g = ((hex_color >> 8) & 0xFF) as u32;
-    let bg_b = (hex_color & 0xFF) as u32;
+struct CachedImage {
+    // A changed file gets a new mtime, which misses the cache. None for downloads.
+    modified: Option<SystemTime>,
+    width: u32,
+    height: u32,
+    bytes: Vec<u8>,
+}
+
+fn image_cache() -> &'static Mutex<Lru<PathBuf, CachedImage>> {
+    IMAGE_CACHE.get_or_init(|| {
+        let max_bytes =
+            capacity_from_env("IMAGE_CACHE_MAX_BYTES", DEFAULT_IMAGE_CACHE_BYTES, "bytes");
+        Mutex::new(Lru::with_weigher(max_bytes, |image: &CachedImage| {
+            image.bytes.len()
+        }))
+    })
+}
+
+fn cached_image(path: &Path, modified: Option<SystemTime>) -> Option<(u32, u32, Vec<u8>)> {
+    let mut cache = image_cache().lock().ok()?;
+    let image = cache.get(path)?;
+    (image.modified == modified).then(|| (image.width, image.height, image.bytes.clone()))
+}
+
+fn cache_image(path: &Path, modified: Option<SystemTime>, width: u32, height: u32, bytes: &[u8]) {
+    if let Ok(mut cache) = image_cache().lock() {
+        cache.insert(
+            path.to_path_buf(),
+            CachedImage {
+                modified,
+                width,
+                height,
+                bytes: bytes.to_vec(),
+            },
+        );
+    }
+}
+
+// Smallest rectangle holding every pixel that isn't fully transparent, grown by `padding` on each
+// side without leaving the image, as (x, y, width, height). None for a fully transparent image.
+pub fn opaque_bounds(image: &image::RgbaImage, padding: u32) -> Option<(u32, u32, u32, u32)> {
+    let (width, height) = image.dimensions();
+    let (mut min_x, mut min_y, mut max_x, mut max_y) = (u32::MAX, u32::MAX, 0, 0);
+    for (x, y, pixel) in image.enumerate_pixels() {
+        if pixel[3] != 0 {
+            min_x = min_x.min(x);
+            min_y = min_y.min(y);
+            max_x = max_x.max(x);
+            max_y = max_y.max(y);
+        }
+    }
+    if min_x == u32::MAX {
+        return None;
+    }
+
+    let x = min_x.saturating_sub(padding);
+    let y = min_y.saturating_sub(padding);
+    let right = (max_x + 1 + padding).min(width);
+    let bottom = (max_y + 1 + padding).min(height);
+    Some((x, y, right - x, bottom - y))
+}
+
+// Half transparent edges are blended in linear light. Mixing the sRGB values directly comes out
+// too dark, which shows as a dark fringe where clothing meets a bright body color. Pixels with an
+// alpha of `threshold` or below count as fully transparent, so templates with faint leftover
+// pixels don't leave a halo; 0 blends everything that isn't completely clear.
+pub fn replace_transparent_with_color(
+    mut bytes: Vec<u8>,
+    hex_color: u32,
+    threshold: u8,
+) -> Vec<u8> {
+    let background = [
+        ((hex_color >> 16) & 0xFF) as u8,
+        ((hex_color >> 8) & 0xFF) as u8,
+        (hex_color & 0xFF) as u8,
+    ];
+    let to_linear = SRGB_TO_LINEAR.get_or_init(|| {
+        std::array::from_fn(|i| {
+            let c = i as f32 / 255.0;
+            if c <= 0.04045 {
+                c / 12.92
+            } else {
+                ((c + 0.055) / 1.055).powf(2.4)
+            }
+        })
+    });
 
     for pixel in bytes.chunks_exact_mut(4) {
-        let alpha = pixel[3] as u32;
+        let alpha = pixel[3];
 
         if alpha == 255 {
             continue;
         }
 
-        if alpha == 0 {
-            pixel[0] = bg_r as u8;
-            pixel[1] = bg_g as u8;
-            pixel[2] = bg_b as u8;
+        if alpha <= threshold {
+            pixel[..3].copy_from_slice(&background);
             pixel[3] = 255;
             continue;
         }
 
-        let inv_alpha = 255 - alpha;
-        pixel[0] = ((pixel[0] as u32 * alpha + bg_r * inv_alpha) / 255) as u8;
-        pixel[1] = ((pixel[1] as u32 * alpha + bg_g * inv_alpha) / 255) as u8;
-        pixel[2] = ((pixel[2] as u32 * alpha + bg_b * inv_alpha) / 255) as u8;
+        let alpha = alpha as f32 / 255.0;
+        for (channel, bg) in pixel[..3].iter_mut().zip(background) {
+            let linear =
+                to_linear[*channel as usize] * alpha + to_linear[bg as usize] * (1.0 - alpha);
+            *channel = linear_to_srgb(linear);
+        }
         pixel[3] = 255;
     }
 
     bytes
 }
 
-pub fn load_static_mesh_from_bytes(name: &str, bytes: &[u8]) -> Option<tobj::Mesh> {
+fn linear_to_srgb(linear: f32) -> u8 {
+    let c = if linear <= 0.0031308 {
+        linear * 12.92
+    } else {
+        1.055 * linear.powf(1.0 / 2.4) - 0.055
+    };
+    (c.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+pub fn load_static_mesh_from_bytes(name: &str, bytes: &[u8]) -> Vec<tobj::Mesh> {
     let mut cursor = Cursor::new(bytes);
     match tobj::load_obj_buf(&mut cursor, &tobj::GPU_LOAD_OPTIONS, |p| {
         // this is gonna return JACKSHIT bro
         tobj::load_mtl(p)
     }) {
-        Ok((meshes, _)) if !meshes.is_empty() => Some(meshes[0].mesh.clone()),
+        Ok((meshes, _)) if !meshes.is_empty() => meshes.into_iter().map(|m| m.mesh).collect(),
         Ok(_) => {
-            eprintln!("Loaded obj from bytes {} but it contained no meshes.", name);
-            None
+            error!("Loaded obj from bytes {} but it contained no meshes.", name);
+            Vec::new()
         }
         Err(err) => {
-            eprintln!("Failed to load static mesh '{}' from bytes: {}", name, err);
-            None
+            error!("Failed to load static mesh '{}' from bytes: {}", name, err);
+            Vec::new()
         }
     }
 }
 
-pub fn load_static_mesh(path: &str) -> Option<tobj::Mesh> {
+// Every mesh in the OBJ, plus the diffuse color of the first one's material if its MTL has one.
+pub fn load_static_mesh(path: &str) -> Option<(Vec<tobj::Mesh>, Option<u32>)> {
     match tobj::load_obj(path, &tobj::GPU_LOAD_OPTIONS) {
-        Ok((meshes, _)) if !meshes.is_empty() => Some(meshes[0].mesh.clone()),
+        Ok((meshes, materials)) if !meshes.is_empty() => {
+            let meshes: Vec<tobj::Mesh> = meshes.into_iter().map(|m| m.mesh).collect();
+            let color = meshes[0]
+                .material_id
+                .and_then(|id| materials.ok()?.get(id)?.diffuse)
+                .map(|[r, g, b]| {
+                    let channel = |c: f32| (c.clamp(0.0, 1.0) * 255.0).round() as u32;
+                    (channel(r) << 16) | (channel(g) << 8) | channel(b)
+                });
+            Some((meshes, color))
+        }
         Ok(_) => {
-            eprintln!("Loaded obj {} but it contained no meshes.", path);
+            error!("Loaded obj {} but it contained no meshes.", path);
             None
         }
         Err(err) => {
-            eprintln!("Failed to load static mesh '{}': {}", path, err);
+            error!("Failed to load static mesh '{}': {}", path, err);
             None
         }
     }
 }
 
-pub fn process_mesh(mesh: &tobj::Mesh, texture: &Texture2D) -> macroquad::models::Mesh {
+// Largest chunk a single draw_mesh call can take with 16-bit indices. main grows macroquad's
+// draw call buffers up to this as bigger meshes come along.
+pub const MESH_CHUNK_VERTICES: usize = u16::MAX as usize;
+pub const MESH_CHUNK_INDICES: usize = MESH_CHUNK_VERTICES * 3;
+
+// Expects triangles. Every OBJ is loaded with tobj::GPU_LOAD_OPTIONS, which fans quads and
+// other polygons into them, so a leftover index count that isn't a multiple of 3 means the mesh
+// is broken and it's skipped rather than drawn garbled.
+pub fn process_mesh(mesh: &tobj::Mesh, texture: &Texture2D) -> Vec<macroquad::models::Mesh> {
+    if !is_triangle_list(&mesh.indices) {
+        warn!(
+            "Skipping mesh with {} indices, which isn't whole triangles",
+            mesh.indices.len()
+        );
+        return Vec::new();
+    }
+
     let vertex_positions: Vec<Vec3> = mesh
         .positions
         .chunks(3)
@@ -196,18 +662,21 @@ pub fn process_mesh(mesh: &tobj::Mesh, texture: &Texture2D) -> macroquad::models
         .map(|x| Vec2::new(x[0], 1.0 - x[1]))
         .collect();
 
-    let normals: Vec<Vec3> = mesh
+    let mut normals: Vec<Vec3> = mesh
         .normals
         .chunks(3)
         .map(|x| Vec3::new(x[0], x[1], x[2]))
         .collect();
+    if normals.len() < vertex_positions.len() {
+        normals = compute_vertex_normals(&vertex_positions, &mesh.indices);
+    }
 
     let mut vertices = Vec::new();
     let count = vertex_positions.len();
 
     for i in 0..count {
         let uv = *texcoords.get(i).unwrap_or(&Vec2::ZERO);
-        
+
         let normal = if i < normals.len() {
             vec4(normals[i].x, normals[i].y, normals[i].z, 1.0)
         } else {
@@ -222,47 +691,404 @@ pub fn process_mesh(mesh: &tobj::Mesh, texture: &Texture2D) -> macroquad::models
         });
     }
 
-    macroquad::models::Mesh {
-        vertices,
-        indices: mesh.indices.iter().map(|x| *x as u16).collect(),
-        texture: Some(texture.clone()),
+    // With every index below a vertex count that fits in a u16 the cast can't wrap. Anything
+    // else goes through split_mesh, which also drops triangles pointing past the vertices.
+    if vertices.len() <= MESH_CHUNK_VERTICES
+        && mesh.indices.len() <= MESH_CHUNK_INDICES
+        && mesh.indices.iter().all(|&i| (i as usize) < vertices.len())
+    {
+        return vec![macroquad::models::Mesh {
+            vertices,
+            indices: mesh.indices.iter().map(|x| *x as u16).collect(),
+            texture: Some(texture.clone()),
+        }];
+    }
+
+    let chunks = split_mesh(&vertices, &mesh.indices, texture);
+    debug!(
+        "Split mesh with {} vertices and {} indices into {} chunks",
+        vertices.len(),
+        mesh.indices.len(),
+        chunks.len()
+    );
+    chunks
+}
+
+fn is_triangle_list(indices: &[u32]) -> bool {
+    indices.len().is_multiple_of(3)
+}
+
+// Smooth normals for meshes exported without any: every vertex gets the sum of the normals of
+// the triangles around it. The cross products aren't normalized first, so bigger triangles
+// weigh more. Vertices no triangle uses point up.
+fn compute_vertex_normals(positions: &[Vec3], indices: &[u32]) -> Vec<Vec3> {
+    let mut normals = vec![Vec3::ZERO; positions.len()];
+
+    for triangle in indices.chunks_exact(3) {
+        let [a, b, c] = [triangle[0], triangle[1], triangle[2]].map(|i| i as usize);
+        if a >= positions.len() || b >= positions.len() || c >= positions.len() {
+            continue;
+        }
+        let face = (positions[b] - positions[a]).cross(positions[c] - positions[a]);
+        normals[a] += face;
+        normals[b] += face;
+        normals[c] += face;
     }
+
+    normals
+        .into_iter()
+        .map(|n| n.try_normalize().unwrap_or(Vec3::Y))
+        .collect()
 }
 
+// Partitions the triangles into chunks that each fit 16-bit indices, copying over only the
+// vertices every chunk actually references.
+fn split_mesh(
+    vertices: &[Vertex],
+    indices: &[u32],
+    texture: &Texture2D,
+) -> Vec<macroquad::models::Mesh> {
+    let mut chunks = Vec::new();
+    let mut remap: HashMap<u32, u16> = HashMap::new();
+    let mut chunk_vertices: Vec<Vertex> = Vec::new();
+    let mut chunk_indices: Vec<u16> = Vec::new();
+
+    for triangle in indices.chunks_exact(3) {
+        if triangle.iter().any(|&i| i as usize >= vertices.len()) {
+            continue;
+        }
+
+        let new_vertices = triangle.iter().filter(|i| !remap.contains_key(i)).count();
+        if chunk_vertices.len() + new_vertices > MESH_CHUNK_VERTICES
+            || chunk_indices.len() + 3 > MESH_CHUNK_INDICES
+        {
+            chunks.push(macroquad::models::Mesh {
+                vertices: std::mem::take(&mut chunk_vertices),
+                indices: std::mem::take(&mut chunk_indices),
+                texture: Some(texture.clone()),
+            });
+            remap.clear();
+        }
+
+        for &index in triangle {
+            let local = *remap.entry(index).or_insert_with(|| {
+                chunk_vertices.push(vertices[index as usize]);
+                (chunk_vertices.len() - 1) as u16
+            });
+            chunk_indices.push(local);
+        }
+    }
+
+    if !chunk_indices.is_empty() {
+        chunks.push(macroquad::models::Mesh {
+            vertices: chunk_vertices,
+            indices: chunk_indices,
+            texture: Some(texture.clone()),
+        });
+    }
+
+    chunks
+}
+
+pub fn transform_mesh(mesh: &mut macroquad::models::Mesh, transform: Mat4) {
+    for vertex in mesh.vertices.iter_mut() {
+        vertex.position = transform.transform_point3(vertex.position);
+        let normal = transform.transform_vector3(vertex.normal.truncate());
+        vertex.normal = vec4(normal.x, normal.y, normal.z, vertex.normal.w);
+    }
+}
+
+// Item scales past these are much more likely a typo than an asset that's really that far off.
+pub fn valid_scale(scale: f32) -> bool {
+    (0.01..=100.0).contains(&scale)
+}
+
+// Scales around the mesh's own origin, so do it before attaching it. Normals get the inverse
+// scale to stay perpendicular when it isn't uniform.
+pub fn scale_mesh(mesh: &mut macroquad::models::Mesh, scale: Vec3) {
+    if scale == Vec3::ONE {
+        return;
+    }
+    for vertex in mesh.vertices.iter_mut() {
+        vertex.position *= scale;
+        let normal = (vertex.normal.truncate() / scale).normalize_or_zero();
+        vertex.normal = vec4(normal.x, normal.y, normal.z, vertex.normal.w);
+    }
+}
+
+pub fn scale_uvs(mesh: &mut macroquad::models::Mesh, factor: f32) {
+    if factor == 1.0 {
+        return;
+    }
+    for vertex in mesh.vertices.iter_mut() {
+        vertex.uv *= factor;
+    }
+}
+
+// `texture_filename` is None for items without a hat_texture, which are drawn plain gray. The
+// checker pattern is kept for textures that are set but fail to load.
 pub fn load_resources_and_mesh(
+    assets: &AssetPaths,
     mesh_filename: &str,
-    texture_filename: &str,
-) -> Result<macroquad::models::Mesh, Box<dyn Error>> {
-    // Ideally, the base path should be configurable, not hardcoded.
-    let texture_full_path = format!("/srv/http/{}", texture_filename);
-    let mesh_full_path = format!("/srv/http/{}", mesh_filename);
-
-    let img_path = Path::new(&texture_full_path);
-
-    let texture = if img_path.exists() {
-        match process_img(img_path) {
-            Ok((w, h, bytes)) => {
-                let img = Image {
-                    bytes,
-                    width: w as u16,
-                    height: h as u16,
-                };
-                Texture2D::from_image(&img)
+    texture_filename: Option<&str>,
+) -> Result<Vec<macroquad::models::Mesh>, ServerError> {
+    let mesh_full_path = assets
+        .resolve(mesh_filename)
+        .map_err(ServerError::MeshLoad)?;
+    let texture = |assets: &AssetPaths| match texture_filename {
+        Some(location) => load_texture_or_checker(assets, location),
+        None => {
+            debug!("{} has no texture, drawing it untextured", mesh_filename);
+            Texture2D::from_rgba8(1, 1, &UNTEXTURED_COLOR)
+        }
+    };
+
+    if is_gltf(&mesh_full_path) {
+        let (mesh_data, embedded) =
+            load_gltf_mesh(&mesh_full_path).map_err(|e| ServerError::MeshLoad(e.to_string()))?;
+        let texture = match embedded {
+            Some(texture) => texture,
+            None => texture(assets),
+        };
+        return Ok(process_mesh(&mesh_data, &texture));
+    }
+
+    let texture = texture(assets);
+    let meshes = load_obj_cached(Path::new(&mesh_full_path))?;
+
+    // Multi-part items (a hat and its brim, say) come as several objects in one file.
+    Ok(meshes
+        .iter()
+        .flat_map(|mesh| process_mesh(mesh, &texture))
+        .collect())
+}
+
+// Reads an accessory's mesh and texture into MESH_CACHE and IMAGE_CACHE off the render thread,
+// so building its scene later finds them there. Failures are left for the render to report.
+pub fn prefetch_item(assets: &AssetPaths, accessory: &ItemAsset) {
+    let location = accessory.location.as_deref().unwrap_or_default();
+    let texture = accessory.texture_path.as_deref().filter(|t| !t.is_empty());
+    match accessory.item_type {
+        9..=12 => {
+            if let Ok(path) = assets.resolve(location)
+                && !is_gltf(&path)
+            {
+                let _ = load_obj_cached(Path::new(&path));
+            }
+            if let Some(texture) = texture {
+                let _ = assets.load_image(texture);
             }
-            Err(_) => Texture2D::from_file_with_format(include_bytes!("checker.png"), None),
         }
-    } else {
-        Texture2D::from_file_with_format(include_bytes!("checker.png"), None)
+        8 => {
+            if let Some(texture) = texture {
+                let _ = assets.load_image(texture);
+            }
+        }
+        // Inline data is decoded by the render, it never goes through the cache.
+        4..=7 if accessory.data.is_none() && !location.is_empty() => {
+            let _ = assets.load_image(location);
+        }
+        _ => {}
+    }
+}
+
+// Parsed OBJ geometry, from MESH_CACHE when the file hasn't changed since it was read. Only the
+// textured macroquad meshes are built again per render.
+fn load_obj_cached(path: &Path) -> Result<Arc<Vec<tobj::Mesh>>, ServerError> {
+    let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+    let cache = MESH_CACHE.get_or_init(|| {
+        let capacity = capacity_from_env("MESH_CACHE_SIZE", DEFAULT_MESH_CACHE_SIZE, "meshes");
+        Mutex::new(Lru::new(capacity))
+    });
+    if let Ok(mut cache) = cache.lock()
+        && let Some(hit) = cache.get(path)
+        && hit.modified == modified
+    {
+        return Ok(hit.meshes.clone());
+    }
+
+    let (models, _) = tobj::load_obj(path, &tobj::GPU_LOAD_OPTIONS)
+        .map_err(|e| ServerError::MeshLoad(e.to_string()))?;
+    if models.is_empty() {
+        return Err(ServerError::MeshLoad("no data found in obj file".into()));
+    }
+
+    let meshes = Arc::new(models.into_iter().map(|m| m.mesh).collect::<Vec<_>>());
+    if let Ok(mut cache) = cache.lock() {
+        cache.insert(
+            path.to_path_buf(),
+            CachedMesh {
+                modified,
+                meshes: meshes.clone(),
+            },
+        );
+    }
+    Ok(meshes)
+}
+
+struct CachedMesh {
+    modified: Option<SystemTime>,
+    meshes: Arc<Vec<tobj::Mesh>>,
+}
+
+fn load_texture_or_checker(assets: &AssetPaths, location: &str) -> Texture2D {
+    match assets.load_image(location) {
+        Ok((w, h, bytes)) => {
+            let img = Image {
+                bytes,
+                width: w as u16,
+                height: h as u16,
+            };
+            Texture2D::from_image(&img)
+        }
+        Err(e) => {
+            warn!(
+                "Texture {} couldn't be loaded, using the checker: {}",
+                location, e
+            );
+            checker_texture()
+        }
+    }
+}
+
+// Stand-in for textures (and with the placeholder cube, meshes) that couldn't be loaded.
+pub fn checker_texture() -> Texture2D {
+    Texture2D::from_file_with_format(include_bytes!("checker.png"), None)
+}
+
+fn is_gltf(path: &str) -> bool {
+    matches!(
+        Path::new(path).extension().and_then(|e| e.to_str()),
+        Some("glb") | Some("gltf")
+    )
+}
+
+// Reads the first primitive of the first mesh into a tobj::Mesh so it goes through the same
+// process_mesh path as OBJs, along with its base color texture if one is embedded.
+fn load_gltf_mesh(path: &str) -> Result<(tobj::Mesh, Option<Texture2D>), Box<dyn Error>> {
+    let (document, buffers, images) = gltf::import(path)?;
+
+    let primitive = document
+        .meshes()
+        .next()
+        .and_then(|m| m.primitives().next())
+        .ok_or("No meshes found in glTF file.")?;
+    let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+
+    let positions: Vec<f32> = reader
+        .read_positions()
+        .ok_or("glTF mesh has no positions.")?
+        .flatten()
+        .collect();
+    let normals: Vec<f32> = reader
+        .read_normals()
+        .map(|n| n.flatten().collect())
+        .unwrap_or_default();
+    // glTF puts the UV origin at the top left, process_mesh expects OBJ's bottom left.
+    let texcoords: Vec<f32> = reader
+        .read_tex_coords(0)
+        .map(|t| t.into_f32().flat_map(|[u, v]| [u, 1.0 - v]).collect())
+        .unwrap_or_default();
+    let indices: Vec<u32> = match reader.read_indices() {
+        Some(i) => i.into_u32().collect(),
+        None => (0..(positions.len() / 3) as u32).collect(),
     };
 
-    let (meshes, _) = tobj::load_obj(&mesh_full_path, &tobj::GPU_LOAD_OPTIONS)?;
+    let mesh = tobj::Mesh {
+        positions,
+        normals,
+        texcoords,
+        indices,
+        ..Default::default()
+    };
 
-    if meshes.is_empty() {
-        return Err("No data found in obj file.".into());
+    let Some(data) = primitive
+        .material()
+        .pbr_metallic_roughness()
+        .base_color_texture()
+        .and_then(|info| images.get(info.texture().source().index()))
+    else {
+        return Ok((mesh, None));
+    };
+
+    let bytes = match data.format {
+        gltf::image::Format::R8G8B8A8 => data.pixels.clone(),
+        gltf::image::Format::R8G8B8 => data
+            .pixels
+            .chunks_exact(3)
+            .flat_map(|p| [p[0], p[1], p[2], 255])
+            .collect(),
+        other => {
+            warn!("Unsupported glTF texture format {:?} in {}", other, path);
+            return Ok((mesh, None));
+        }
+    };
+    // Textures are sized in u16, a bigger one would wrap around and read the wrong pixels.
+    let (Ok(width), Ok(height)) = (u16::try_from(data.width), u16::try_from(data.height)) else {
+        return Err(format!(
+            "glTF texture in {} is {}x{}, too large to load",
+            path, data.width, data.height
+        )
+        .into());
+    };
+
+    Ok((mesh, Some(Texture2D::from_rgba8(width, height, &bytes))))
+}
+
+// Solid body part colors, shared across renders on the render thread.
+// Everything goes through `texture` so this can later become a material color uniform.
+// Inline renders take any hex color, so COLOR_CACHE_SIZE bounds how many textures are kept.
+pub struct ColorTextureCache {
+    textures: Lru<u32, Texture2D>,
+}
+
+impl ColorTextureCache {
+    pub fn from_env() -> ColorTextureCache {
+        let capacity = capacity_from_env("COLOR_CACHE_SIZE", DEFAULT_COLOR_CACHE_SIZE, "colors");
+        ColorTextureCache {
+            textures: Lru::new(capacity),
+        }
+    }
+
+    pub fn texture(&mut self, hex: u32) -> Texture2D {
+        if let Some(texture) = self.textures.get(&hex) {
+            return texture.clone();
+        }
+        let texture = Texture2D::from_rgba8(1, 1, &from_hex(hex));
+        self.textures.insert(hex, texture.clone());
+        texture
     }
+}
 
-    let mesh_data = meshes[0].mesh.clone();
-    Ok(process_mesh(&mesh_data, &texture))
+// Peak resident memory of this process in KB (VmHWM), None where /proc isn't available.
+pub fn peak_memory_kb() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    line.split_whitespace().nth(1)?.parse().ok()
+}
+
+// Drops the host part of an address (last octet of IPv4, last 80 bits of IPv6) for logging.
+pub fn mask_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, c, _] = v4.octets();
+            IpAddr::V4(Ipv4Addr::new(a, b, c, 0))
+        }
+        IpAddr::V6(v6) => {
+            let prefix = u128::from(v6) & !((1u128 << 80) - 1);
+            IpAddr::V6(Ipv6Addr::from(prefix))
+        }
+    }
+}
+
+// Accepts `RRGGBB` with or without a leading `#`.
+pub fn parse_hex_color(value: &str) -> Option<u32> {
+    let digits = value.strip_prefix('#').unwrap_or(value);
+    // from_str_radix also takes a leading sign, so "+80808" would pass.
+    if digits.len() != 6 || !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    u32::from_str_radix(digits, 16).ok()
 }
 
 pub fn from_hex(hex: u32) -> [u8; 4] {
@@ -272,7 +1098,42 @@ pub fn from_hex(hex: u32) -> [u8; 4] {
     [byte_1, byte_2, byte_3, 255]
 }
 
+// Reads BRICKCOLOR_PALETTE, a JSON object of brickcolor id to hex color like
+// {"1001": "F8F8F8", "5000": "#3A7BD5"}. Entries override or extend the built-in table.
+pub fn load_brickcolor_palette() {
+    let Ok(path) = std::env::var("BRICKCOLOR_PALETTE") else {
+        return;
+    };
+    if path.is_empty() {
+        return;
+    }
+
+    let contents = std::fs::read_to_string(&path).expect("Failed to read BRICKCOLOR_PALETTE");
+    let entries: HashMap<String, String> =
+        serde_json::from_str(&contents).expect("BRICKCOLOR_PALETTE must be a JSON object");
+
+    let mut palette = HashMap::new();
+    for (id, hex) in entries {
+        match (id.parse::<u16>(), parse_hex_color(&hex)) {
+            (Ok(id), Some(hex)) => {
+                palette.insert(id, hex);
+            }
+            _ => warn!("Ignoring invalid palette entry {:?}: {:?}", id, hex),
+        }
+    }
+
+    info!("Loaded {} custom brickcolors", palette.len());
+    let _ = BRICKCOLOR_PALETTE.set(palette);
+}
+
 pub fn from_brickcolor(id: u16) -> Option<u32> {
+    if let Some(hex) = BRICKCOLOR_PALETTE
+        .get()
+        .and_then(|palette| palette.get(&id))
+    {
+        return Some(*hex);
+    }
+
     // Optimized: Replaced HashMap construction with a match expression (Jump Table)
     match id {
         1003 => Some(0x111111),
@@ -457,6 +1318,165 @@ pub fn from_brickcolor(id: u16) -> Option<u32> {
         113 => Some(0xE5ADC8),
         9 => Some(0xE8BAC8),
         223 => Some(0xDC9095),
+        // The rest of the palette, mostly the greys and legacy colors.
+        1 => Some(0xF2F3F3),
+        27 => Some(0x6D6E6C),
+        39 => Some(0xC1CADE),
+        50 => Some(0xECE8DE),
+        103 => Some(0xC7C1B7),
+        111 => Some(0xBFB7B1),
+        131 => Some(0x9CA3A8),
+        136 => Some(0x877C90),
+        146 => Some(0x958EA3),
+        147 => Some(0x938767),
+        150 => Some(0xABADAC),
+        154 => Some(0x7B2E2F),
+        179 => Some(0x898788),
+        194 => Some(0xA3A2A5),
+        199 => Some(0x635F62),
+        208 => Some(0xE5E4DF),
+        211 => Some(0x79B5B5),
+        302 => Some(0x5B5D69),
+        311 => Some(0x9FA1AC),
+        320 => Some(0xCACBD1),
+        325 => Some(0xDFDFDE),
+        335 => Some(0xE7E7EC),
+        354 => Some(0xBBB3B2),
+        357 => Some(0x958988),
+        358 => Some(0xABA89E),
+        363 => Some(0x69665C),
         _ => None,
     }
-}
\ No newline at end of file
+}
+
+// For drawing: ids the palette doesn't know come out Medium stone grey (194) instead of black,
+// so a missing entry is noticeable without looking like a deliberate color choice.
+pub fn from_brickcolor_or_default(id: u16) -> u32 {
+    from_brickcolor(id).unwrap_or(UNKNOWN_BRICKCOLOR)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cube_normals_point_outward() {
+        let positions: Vec<Vec3> = (0..8)
+            .map(|i| Vec3::new((i & 1) as f32, (i >> 1 & 1) as f32, (i >> 2 & 1) as f32))
+            .collect();
+        // Two counter-clockwise triangles per face, seen from outside.
+        let indices = [
+            0, 2, 1, 1, 2, 3, // z = 0
+            4, 5, 6, 5, 7, 6, // z = 1
+            0, 1, 4, 1, 5, 4, // y = 0
+            2, 6, 3, 3, 6, 7, // y = 1
+            0, 4, 2, 2, 4, 6, // x = 0
+            1, 3, 5, 3, 7, 5, // x = 1
+        ];
+
+        let normals = compute_vertex_normals(&positions, &indices);
+        let center = Vec3::splat(0.5);
+        for (position, normal) in positions.iter().zip(&normals) {
+            let outward = (*position - center).normalize();
+            assert!((normal.length() - 1.0).abs() < 1e-5);
+            assert!(normal.dot(outward) > 0.5, "{} at {}", normal, position);
+        }
+    }
+
+    #[test]
+    fn half_transparent_pixels_blend_in_linear_light() {
+        let blended = replace_transparent_with_color(vec![0, 0, 0, 128], 0xFFFFFF, 0);
+        let naive = (255.0_f32 * (1.0 - 128.0 / 255.0)).round() as u8;
+        assert_eq!(blended[3], 255);
+        assert!(blended[..3].iter().all(|&c| c == blended[0]));
+        assert!((186..=190).contains(&blended[0]), "got {}", blended[0]);
+        assert_ne!(blended[0], naive);
+
+        let opaque = replace_transparent_with_color(vec![10, 20, 30, 255], 0xFFFFFF, 0);
+        assert_eq!(opaque, [10, 20, 30, 255]);
+    }
+
+    #[test]
+    fn pixels_at_or_below_the_alpha_threshold_become_the_background() {
+        let pixels = vec![200, 0, 0, 10, 200, 0, 0, 11, 200, 0, 0, 0];
+        let out = replace_transparent_with_color(pixels, 0x102030, 10);
+        assert_eq!(out[..4], [0x10, 0x20, 0x30, 255]);
+        assert_ne!(out[4..7], [0x10, 0x20, 0x30]);
+        assert_eq!(out[7], 255);
+        assert_eq!(out[8..], [0x10, 0x20, 0x30, 255]);
+    }
+
+    #[test]
+    fn quads_load_as_triangles() {
+        let obj = b"v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nf 1 2 3 4\n";
+        let meshes = load_static_mesh_from_bytes("quad", obj);
+        assert_eq!(meshes.len(), 1);
+        assert_eq!(meshes[0].indices.len(), 6);
+        assert!(is_triangle_list(&meshes[0].indices));
+        assert!(meshes[0].indices.iter().all(|&i| i < 4));
+    }
+
+    #[test]
+    fn leftover_indices_are_not_triangles() {
+        assert!(is_triangle_list(&[]));
+        assert!(is_triangle_list(&[0, 1, 2, 2, 3, 0]));
+        assert!(!is_triangle_list(&[0, 1, 2, 3]));
+    }
+
+    #[test]
+    fn accessory_ids_are_bound_not_formatted() {
+        for has_scale in [true, false] {
+            let query = accessories_query(&[1234, 5678, 9012], has_scale);
+            let sql = query.sql();
+            assert!(sql.contains("IN (?, ?, ?)"), "{}", sql);
+            for id in ["1234", "5678", "9012"] {
+                assert!(!sql.contains(id), "{}", sql);
+            }
+        }
+    }
+
+    #[test]
+    fn bad_body_colors_fall_back_to_grey() {
+        let json = r#"{"trso": 70000, "head": -5, "lleg": "not a color", "larm": "21",
+            "rarm": 9999, "rleg": 24}"#;
+        let colors = BodyColors::from_column(json, "test");
+
+        assert_eq!(colors.trso, UNKNOWN_BRICKCOLOR_ID);
+        assert_eq!(colors.head, UNKNOWN_BRICKCOLOR_ID);
+        assert_eq!(colors.lleg, UNKNOWN_BRICKCOLOR_ID);
+        assert_eq!(colors.larm, 21);
+        assert_eq!(colors.rarm, UNKNOWN_BRICKCOLOR_ID);
+        assert_eq!(colors.rleg, 24);
+    }
+
+    #[test]
+    fn brickcolors_map_to_hex_and_unknown_ids_to_grey() {
+        assert_eq!(from_brickcolor(1001), Some(0xF8F8F8));
+        assert_eq!(from_brickcolor(21), Some(0xC4281C));
+        assert_eq!(from_brickcolor(1), Some(0xF2F3F3));
+        assert_eq!(from_brickcolor_or_default(24), 0xF5CD30);
+
+        assert_eq!(from_brickcolor(0), None);
+        assert_eq!(from_brickcolor(60000), None);
+        assert_eq!(from_brickcolor_or_default(0), 0xA3A2A5);
+        assert_eq!(from_brickcolor_or_default(60000), UNKNOWN_BRICKCOLOR);
+    }
+
+    #[test]
+    fn hex_colors_need_six_hex_digits() {
+        assert_eq!(parse_hex_color("3A7BD5"), Some(0x3A7BD5));
+        assert_eq!(parse_hex_color("#3a7bd5"), Some(0x3A7BD5));
+        assert_eq!(parse_hex_color("+80808"), None);
+        assert_eq!(parse_hex_color("-80808"), None);
+        assert_eq!(parse_hex_color("80808"), None);
+        assert_eq!(parse_hex_color("80808G"), None);
+    }
+
+    #[test]
+    fn unknown_profile_colors_are_drawn_grey() {
+        let json = r#"{"trso": 9999, "head": 1001, "lleg": 1, "larm": 1, "rarm": 1, "rleg": 1}"#;
+        let colors = BodyColors::from_column(json, "test");
+        assert_eq!(from_brickcolor_or_default(colors.trso), 0xA3A2A5);
+        assert_eq!(from_brickcolor_or_default(colors.head), 0xF8F8F8);
+    }
+}
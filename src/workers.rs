@@ -0,0 +1,236 @@
+// Multi-process rendering. macroquad owns exactly one GL context per process, so the only way to
+// render in parallel is to run several processes. With `--workers N` the front-end process keeps
+// the HTTP server and the database, and spawns N copies of itself with `--worker <socket>`. Each
+// worker opens its own window and runs the normal render loop, fed over a Unix socket.
+//
+// Frames on the socket are a big-endian u32 length followed by that many bytes: a JSON RenderJob
+// from the front-end, answered by the JSON RenderAnswer from the worker.
+
+use std::io::{self, Read, Write};
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::process::{Child, Command};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{Receiver, SyncSender, channel};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{env, fs, process, thread};
+
+use log::{error, info};
+
+use crate::error::ServerError;
+use crate::{QUEUE_DEPTH, RenderJob, RenderRequest, render_timeout};
+
+// How long a freshly spawned worker gets to open its window and connect back.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+// Pause between attempts when a worker won't come back up.
+const RESPAWN_DELAY: Duration = Duration::from_secs(5);
+
+fn write_frame(stream: &mut UnixStream, bytes: &[u8]) -> io::Result<()> {
+    stream.write_all(&(bytes.len() as u32).to_be_bytes())?;
+    stream.write_all(bytes)
+}
+
+fn read_frame(stream: &mut UnixStream) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len)?;
+    let mut bytes = vec![0u8; u32::from_be_bytes(len) as usize];
+    stream.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+struct Worker {
+    child: Child,
+    stream: UnixStream,
+}
+
+// Starts worker processes and waits for them to connect. The socket lives in a directory only
+// this user can enter, and stays bound so dead workers can be replaced.
+struct Spawner {
+    exe: PathBuf,
+    socket_path: PathBuf,
+    // Held while a worker is starting, so each connection is matched to the child it came from.
+    listener: Mutex<UnixListener>,
+}
+
+impl Spawner {
+    fn new() -> io::Result<Spawner> {
+        let dir = env::temp_dir().join(format!("lsd-render-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::DirBuilder::new().mode(0o700).create(&dir)?;
+        let socket_path = dir.join("workers.sock");
+        let listener = UnixListener::bind(&socket_path)?;
+        listener.set_nonblocking(true)?;
+
+        Ok(Spawner {
+            exe: env::current_exe()?,
+            socket_path,
+            listener: Mutex::new(listener),
+        })
+    }
+
+    fn start(&self) -> io::Result<Worker> {
+        let listener = self
+            .listener
+            .lock()
+            .map_err(|_| io::Error::other("worker listener poisoned"))?;
+        let mut child = Command::new(&self.exe)
+            .arg("--worker")
+            .arg(&self.socket_path)
+            .spawn()?;
+
+        let deadline = Instant::now() + CONNECT_TIMEOUT;
+        let failed = loop {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(false)?;
+                    // A worker renders one job at a time, so nothing legitimate takes longer.
+                    stream.set_read_timeout(Some(render_timeout()))?;
+                    return Ok(Worker { child, stream });
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => break e,
+            }
+            if let Ok(Some(status)) = child.try_wait() {
+                return Err(io::Error::other(format!(
+                    "exited before connecting: {}",
+                    status
+                )));
+            }
+            if Instant::now() >= deadline {
+                break io::Error::new(io::ErrorKind::TimedOut, "didn't connect in time");
+            }
+            thread::sleep(Duration::from_millis(50));
+        };
+        let _ = child.kill();
+        let _ = child.wait();
+        Err(failed)
+    }
+
+    // Kills what's left of a worker that failed and starts a new one, trying until it works.
+    fn restart(&self, id: usize, mut worker: Worker) -> Worker {
+        let _ = worker.child.kill();
+        if let Ok(status) = worker.child.wait() {
+            error!("Render worker {} exited: {}", id, status);
+        }
+        loop {
+            match self.start() {
+                Ok(worker) => {
+                    info!("RENDER WORKER {} RESTARTED", id);
+                    return worker;
+                }
+                Err(e) => {
+                    error!("Render worker {} couldn't be restarted: {}", id, e);
+                    thread::sleep(RESPAWN_DELAY);
+                }
+            }
+        }
+    }
+}
+
+// Front-end side. Every worker gets a dispatcher thread that pulls the next request off the
+// shared queue, so an idle worker always picks up the oldest pending job. A worker that dies or
+// hangs is replaced, so the pool keeps its size.
+pub fn spawn_workers(count: usize, rx_work: Receiver<RenderRequest>) {
+    let spawner = Arc::new(Spawner::new().expect("Failed to bind worker socket"));
+    let rx_work = Arc::new(Mutex::new(rx_work));
+    for id in 0..count {
+        let worker = spawner.start().expect("Failed to start render worker");
+        let spawner = Arc::clone(&spawner);
+        let rx_work = Arc::clone(&rx_work);
+        thread::spawn(move || dispatch(id, worker, spawner, rx_work));
+    }
+
+    info!("{} RENDER WORKERS CONNECTED", count);
+}
+
+fn dispatch(
+    id: usize,
+    mut worker: Worker,
+    spawner: Arc<Spawner>,
+    rx_work: Arc<Mutex<Receiver<RenderRequest>>>,
+) {
+    loop {
+        let request = match rx_work.lock() {
+            Ok(rx) => match rx.recv() {
+                Ok(request) => {
+                    QUEUE_DEPTH.fetch_sub(1, Ordering::Relaxed);
+                    request
+                }
+                Err(_) => return,
+            },
+            Err(_) => return,
+        };
+
+        let stream = &mut worker.stream;
+        let result = serde_json::to_vec(&request.job)
+            .map_err(io::Error::other)
+            .and_then(|job| write_frame(stream, &job))
+            .and_then(|_| read_frame(stream));
+
+        match result {
+            Ok(bytes) => {
+                let answer = serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+                    error!("Render worker {} sent a malformed result: {}", id, e);
+                    Err(ServerError::RenderFailed("malformed worker result".into()))
+                });
+                let _ = request.response_sender.send(answer);
+            }
+            Err(e) => {
+                let hung = matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                );
+                error!("Render worker {} failed: {}", id, e);
+                let answer = if hung {
+                    ServerError::TimedOut
+                } else {
+                    ServerError::RenderFailed("render worker died".into())
+                };
+                let _ = request.response_sender.send(Err(answer));
+                worker = spawner.restart(id, worker);
+            }
+        }
+    }
+}
+
+// Worker side. Jobs are forwarded into the local render loop one at a time; the worker exits
+// as soon as the front-end goes away.
+pub fn serve_jobs(socket_path: &str, tx_work: SyncSender<RenderRequest>) {
+    let mut stream = UnixStream::connect(socket_path).expect("Failed to connect to front-end");
+
+    thread::spawn(move || {
+        loop {
+            let Ok(bytes) = read_frame(&mut stream) else {
+                process::exit(0);
+            };
+
+            let result = match serde_json::from_slice::<RenderJob>(&bytes) {
+                Ok(job) => {
+                    let (tx_answer, rx_answer) = channel();
+                    let request = RenderRequest {
+                        job,
+                        response_sender: tx_answer,
+                    };
+                    QUEUE_DEPTH.fetch_add(1, Ordering::Relaxed);
+                    if tx_work.send(request).is_err() {
+                        process::exit(1);
+                    }
+                    rx_answer.recv().unwrap_or_else(|_| {
+                        Err(ServerError::RenderFailed("render loop went away".into()))
+                    })
+                }
+                Err(e) => {
+                    error!("Received malformed job: {}", e);
+                    Err(ServerError::RenderFailed("malformed job".into()))
+                }
+            };
+
+            let bytes = serde_json::to_vec(&result).unwrap_or_default();
+            if write_frame(&mut stream, &bytes).is_err() {
+                process::exit(0);
+            }
+        }
+    });
+}